@@ -2,6 +2,10 @@
 
 "use strict";
 
+const WASM_PAGE_SIZE = 65536;
+/** Version of the import modules implemented by this host. */
+const ABI_VERSION = 1;
+
 class Runner extends EventTarget {
 	constructor() {
 		super();
@@ -16,6 +20,31 @@ class Runner extends EventTarget {
 	instantiate(module, instance) {
 		this.module = module;
 		this.instance = instance;
+		this.capabilities = this.checkAbi();
+	}
+
+	/**
+	 * Checks the guest's `__rs_wasm_alloc_abi_version` export against
+	 * `ABI_VERSION`.
+	 * @returns {number | undefined} the guest's capabilities, or `undefined`
+	 * if it exports no version
+	 */
+	checkAbi() {
+		const version = this.getExportedFunction("__rs_wasm_alloc_abi_version");
+		if (version === undefined) { return undefined; }
+
+		const guestVersion = version();
+		if (guestVersion !== ABI_VERSION) {
+			throw new FatalError(
+				`guest was built for version ${guestVersion} of the ` +
+				`rs-wasm-alloc ABI, but the host implements version ${ABI_VERSION}`
+			);
+		}
+
+		const capabilities = this.getExportedFunction(
+			"__rs_wasm_alloc_capabilities"
+		);
+		return capabilities === undefined ? 0 : capabilities();
 	}
 
 	/**
@@ -48,6 +77,46 @@ class Runner extends EventTarget {
 		return memory;
 	}
 
+	/**
+	 * Returns the address where the guest's heap may begin, or 0 if the guest
+	 * does not tell.
+	 * @returns {number}
+	 */
+	heapBase() {
+		const heapBase = this.getExportedFunction("__rs_wasm_alloc_heap_base");
+		if (heapBase !== undefined) {
+			return heapBase();
+		}
+
+		const global = this.instance.exports["__heap_base"];
+		if (global instanceof WebAssembly.Global) {
+			return global.value;
+		}
+
+		return 0;
+	}
+
+	/**
+	 * Grows memory through the guest's `__rs_wasm_alloc_grow` export, or
+	 * directly if the guest does not export it.
+	 * @param {number} pages
+	 * @returns {boolean} whether memory was grown
+	 */
+	growMemory(pages) {
+		const grow = this.getExportedFunction("__rs_wasm_alloc_grow");
+		if (grow !== undefined) {
+			return grow(pages) !== -1;
+		}
+
+		try {
+			this.memory().grow(pages);
+			return true;
+		} catch (error) {
+			if (error instanceof RangeError) { return false; }
+			throw error;
+		}
+	}
+
 	writeU8(offset, value){
 		(new Uint8Array(this.memory().buffer, offset, 1))[0] = value;
 	}
@@ -98,12 +167,33 @@ class Runner extends EventTarget {
 	}
 }
 
+/** Misconfiguration of the guest or the host that prevents running it. */
+class FatalError extends Error {}
+
 class WasmPanicError extends Error {
-	constructor(message, file, line, column) {
+	static payloadKinds = ['unknown', 'literal', 'formatted', 'string'];
+	static abortReasons = [
+		'unknown',
+		'panic', 'alloc-failure', 'abi-mismatch', 'corrupted-state', 'assertion'
+	];
+
+	constructor(message, file, line, column, payloadKind = 0, abortReason = 0) {
 		super(message);
 		this.file = file;
 		this.line = line;
 		this.column = column;
+		this.payloadKind = WasmPanicError.payloadKinds[payloadKind] ?? 'unknown';
+		this.abortReason = WasmPanicError.abortReasons[abortReason] ?? 'unknown';
+	}
+}
+
+class WasiExitError extends Error {
+	/**
+	 * @param {number} code
+	 */
+	constructor(code) {
+		super(`guest exited with code ${code}`);
+		this.code = code;
 	}
 }
 
@@ -252,6 +342,8 @@ class PanicModule extends WasmModule {
 		this.file = undefined;
 		this.line = 0;
 		this.column = 0;
+		this.payloadKind = 0;
+		this.abortReason = 0;
 	}
 
 	panic_ch(ch) {
@@ -272,25 +364,86 @@ class PanicModule extends WasmModule {
 		this.column = column;
 	}
 
+	/**
+	 * @param {number} kind 0 if unknown, 1 for literals, 2 for formatted
+	 * messages and 3 for `String`s
+	 */
+	panic_put_payload_kind(kind) {
+		this.payloadKind = kind;
+	}
+
+	/**
+	 * @param {number} reason index into `WasmPanicError.abortReasons`
+	 */
+	panic_put_abort_reason(reason) {
+		this.abortReason = reason;
+	}
+
 	panic() {
-		const line = this.line;
-		const column = this.column;
+		PanicModule.throw(
+			this.message, this.file, this.line, this.column,
+			this.payloadKind, this.abortReason
+		);
+	}
+
+	/**
+	 * Panics after the guest panicked while panicking, with only the location
+	 * of the nested panic.
+	 */
+	panic_nested(pointer, length, line) {
+		const file = this.runner.decodeUtf8(pointer, length);
+		throw new WasmPanicError("panicked while panicking", file, line, 0);
+	}
 
-		let file = this.file;
+	/**
+	 * Panics with a `PanicReport`, which holds the message and file as
+	 * pointer-length pairs, then the line, column, payload kind and abort
+	 * reason.
+	 * @param {number} pointer
+	 */
+	panic_report(pointer) {
+		const view = new DataView(this.runner.memory().buffer, pointer, 32);
+		const field = (i) => view.getUint32(i * 4, true);
+
+		const message = this.runner.decodeUtf8(field(0), field(1));
+		let file = undefined;
+		if (field(2) !== 0) {
+			file = this.runner.decodeUtf8(field(2), field(3));
+		}
+		PanicModule.throw(
+			message, file, field(4), field(5), field(6), field(7)
+		);
+	}
+
+	/**
+	 * @param {string} message
+	 * @param {string | undefined} file
+	 * @param {number} line
+	 * @param {number} column
+	 * @param {number} payloadKind
+	 * @param {number} abortReason
+	 */
+	static throw(message, file, line, column, payloadKind, abortReason) {
 		if (file === undefined) {
 			file = '?';
 		}
 
-		let message = this.message;
 		if (message === '') {
 			message = `<no message, ${file}:${line}:${column}>`;
 		}
 
-		throw new WasmPanicError(message, file, line, column);
+		throw new WasmPanicError(
+			message, file, line, column, payloadKind, abortReason
+		);
 	}
 }
 
 class DebugModule extends WasmModule {
+	static consoleMethods = [
+		undefined,
+		console.error, console.warn, console.info, console.debug, console.debug
+	];
+
 	/**
 	 * @param {Runner} runner
 	 */
@@ -298,6 +451,8 @@ class DebugModule extends WasmModule {
 		super();
 		this.runner = runner;
 		this.dblog = [];
+		/** @type {Map<number, string[]>} */
+		this.streams = new Map();
 	}
 
 	dblog_ch(code) {
@@ -313,6 +468,53 @@ class DebugModule extends WasmModule {
 		this.dblog = [];
 		console.debug(data);
 	}
+
+	/**
+	 * @param {number} stream 1 for `stdout`, 2 for `stderr`
+	 */
+	dblog_write(stream, pointer, length) {
+		let pending = this.streams.get(stream);
+		if (pending === undefined) {
+			pending = [];
+			this.streams.set(stream, pending);
+		}
+		pending.push(this.runner.decodeUtf8(pointer, length));
+	}
+
+	dblog_flush_stream(stream) {
+		const data = (this.streams.get(stream) ?? []).join('');
+		this.streams.delete(stream);
+		(stream === 2 ? console.error : console.log)(data);
+	}
+
+	/**
+	 * Logs the pending message as a record of the `log` crate.
+	 * @param {number} level from 1 for errors to 5 for traces
+	 */
+	dblog_record(
+		level,
+		targetPointer, targetLength,
+		modulePathPointer, modulePathLength,
+		filePointer, fileLength,
+		line
+	) {
+		const message = this.dblog.join('');
+		this.dblog = [];
+
+		const target = this.runner.decodeUtf8(targetPointer, targetLength);
+		let location = '';
+		if (filePointer !== 0) {
+			const file = this.runner.decodeUtf8(filePointer, fileLength);
+			location = ` (${file}:${line})`;
+		} else if (modulePathPointer !== 0) {
+			location = ` (${this.runner.decodeUtf8(
+				modulePathPointer, modulePathLength
+			)})`;
+		}
+
+		const log = DebugModule.consoleMethods[level] ?? console.debug;
+		log(`[${target}] ${message}${location}`);
+	}
 }
 
 class AllocBlock {
@@ -335,6 +537,57 @@ class AllocBlock {
 	}
 }
 
+/**
+ * The part of `wasi_snapshot_preview1` that guests built with the `wasi`
+ * feature import instead of the `panic` and `debug` modules.
+ */
+class WasiModule extends WasmModule {
+	static ERRNO_SUCCESS = 0;
+	static ERRNO_BADF = 8;
+
+	/**
+	 * @param {Runner} runner
+	 */
+	constructor(runner) {
+		super();
+		this.runner = runner;
+		/** @type {Map<number, string>} */
+		this.pending = new Map();
+	}
+
+	/**
+	 * Writes to `stdout` or `stderr`, logging every complete line.
+	 * @param {number} fd 1 for `stdout`, 2 for `stderr`
+	 */
+	fd_write(fd, iovsPointer, iovsLength, writtenPointer) {
+		if (fd !== 1 && fd !== 2) { return WasiModule.ERRNO_BADF; }
+
+		const view = new DataView(this.runner.memory().buffer);
+		let written = 0;
+		let data = this.pending.get(fd) ?? '';
+		for (let i = 0; i < iovsLength; ++i) {
+			const pointer = view.getUint32(iovsPointer + i * 8, true);
+			const length = view.getUint32(iovsPointer + i * 8 + 4, true);
+			data += this.runner.decodeUtf8(pointer, length);
+			written += length;
+		}
+
+		const lines = data.split('\n');
+		this.pending.set(fd, lines.pop());
+		const log = fd === 2 ? console.error : console.log;
+		for (const line of lines) {
+			log(line);
+		}
+
+		view.setUint32(writtenPointer, written, true);
+		return WasiModule.ERRNO_SUCCESS;
+	}
+
+	proc_exit(code) {
+		throw new WasiExitError(code);
+	}
+}
+
 class AllocModule extends WasmModule {
 	/**
 	 * @param {Runner} runner
@@ -354,6 +607,16 @@ class AllocModule extends WasmModule {
 		return Math.ceil(address / align) * align;
 	}
 
+	/**
+	 * Returns the lowest address a block may start at.
+	 * @param {AllocModule} self
+	 * @returns {number}
+	 */
+	static firstPointer(self) {
+		// Never hand out null, nor memory below the guest's heap.
+		return Math.max(self.runner.heapBase(), 1);
+	}
+
 	/**
 	 * @param {AllocModule} self
 	 * @param {number} size
@@ -375,24 +638,23 @@ class AllocModule extends WasmModule {
 
 				if (block.size >= size) {
 					const prevBlock = blocks[i - 1];
-					if (prevBlock !== undefined) {
-						const alignedPointer = AllocModule.alignPointer(
-							prevBlock.nextPointer(), align
-						);
-
-						const nextBlock = blocks[i + 1];
-						if (nextBlock !== undefined) {
-							if (AllocBlock.nextPointerWith(
-								alignedPointer, size
-							) > nextBlock.pointer)
-							{
-								continue;
-							}
+					const start = prevBlock !== undefined
+						? prevBlock.nextPointer()
+						: AllocModule.firstPointer(self);
+					const alignedPointer = AllocModule.alignPointer(start, align);
+
+					const nextBlock = blocks[i + 1];
+					if (nextBlock !== undefined) {
+						if (AllocBlock.nextPointerWith(
+							alignedPointer, size
+						) > nextBlock.pointer)
+						{
+							continue;
 						}
-
-						block.pointer = alignedPointer;
 					}
 
+					block.pointer = alignedPointer;
+
 					block.used = true;
 					block.size = size;
 					existingBlock = block;
@@ -413,15 +675,63 @@ class AllocModule extends WasmModule {
 			blocks.push(existingBlock);
 			return existingBlock;
 		} else {
-			const block = new AllocBlock(align, size);
+			const block = new AllocBlock(
+				AllocModule.alignPointer(AllocModule.firstPointer(self), align),
+				size
+			);
 			blocks.push(block);
 			return block;
 		}
 	}
 
+	/**
+	 * Like `newBlock`, but grows memory to fit the block, and gives up on it
+	 * if memory cannot be grown.
+	 * @param {AllocModule} self
+	 * @param {number} size
+	 * @param {number} align
+	 * @returns {AllocBlock | undefined}
+	 */
+	static newBlockInMemory(self, size, align) {
+		const block = AllocModule.newBlock(self, size, align);
+
+		const end = block.nextPointer();
+		const memorySize = self.runner.memory().buffer.byteLength;
+		if (end > memorySize) {
+			const pages = Math.ceil((end - memorySize) / WASM_PAGE_SIZE);
+			if (!self.runner.growMemory(pages)) {
+				block.used = false;
+				return undefined;
+			}
+		}
+
+		return block;
+	}
+
 	alloc(size, align) {
 		// console.debug("alloc", size, align);
-		return AllocModule.newBlock(this, size, align).pointer;
+		const block = AllocModule.newBlockInMemory(this, size, align);
+		if (block === undefined) { return 0; }
+		return block.pointer;
+	}
+
+	alloc_zeroed(size, align) {
+		// console.debug("alloc_zeroed", size, align);
+		const block = AllocModule.newBlockInMemory(this, size, align);
+		if (block === undefined) { return 0; }
+		this.runner.bytes(block.pointer, size).fill(0);
+		return block.pointer;
+	}
+
+	abi_version() {
+		return ABI_VERSION;
+	}
+
+	oom(size, align) {
+		console.warn(
+			`out of memory for a block ` +
+			`of size ${size} with alignment ${align}`
+		);
 	}
 
 	dealloc(pointer, size, align) {
@@ -477,17 +787,17 @@ class AllocModule extends WasmModule {
 
 		// !!! CRITICAL SECTION !!!
 		reallocBlock.used = false;
-		const origData = new Uint8Array(
-			this.runner.memory(),
-			reallocBlock.pointer, size
-		);
+		const newBlock = AllocModule.newBlockInMemory(this, newSize, align);
+		if (newBlock === undefined) {
+			reallocBlock.used = true;
+			return 0;
+		}
 
-		const newBlock = AllocModule.newBlock(this, newSize, align);
-		const newData = new Uint8Array(
-			this.runner.memory(),
-			newBlock.pointer, newSize
+		// The new block may overlap the old one if it was reused.
+		new Uint8Array(this.runner.memory().buffer).copyWithin(
+			newBlock.pointer,
+			pointer, pointer + Math.min(size, newSize)
 		);
-		newData.set(origData);
 
 		return newBlock.pointer;
 	}
@@ -501,7 +811,8 @@ class AllocModule extends WasmModule {
 		{
 			"alloc": (new AllocModule(runner)).getExports(),
 			"debug": (new DebugModule(runner)).getExports(),
-			"panic": (new PanicModule(runner)).getExports()
+			"panic": (new PanicModule(runner)).getExports(),
+			"wasi_snapshot_preview1": (new WasiModule(runner)).getExports()
 		}
 	);
 
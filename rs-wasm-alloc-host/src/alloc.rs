@@ -0,0 +1,422 @@
+//! Host side of the `alloc` import module.
+
+use std::error::Error;
+use std::fmt;
+
+/// Block of guest memory handed out by a [`BlockTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+	pub pointer: u32,
+	pub size: u32,
+	pub used: bool
+}
+
+impl Block {
+	pub const fn new(pointer: u32, size: u32) -> Self {
+		Self {
+			pointer,
+			size,
+			used: true
+		}
+	}
+
+	/// Returns the address right past the end of this block.
+	pub const fn next_pointer(&self) -> Option<u32> {
+		self.pointer.checked_add(self.size)
+	}
+}
+
+/// Misuse of the `alloc` import module by the guest.
+///
+/// The variants mirror the errors thrown by `AllocModule` in `index.js`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+	InvalidAlignment {
+		align: u32
+	},
+	AddressSpaceExhausted {
+		size: u32,
+		align: u32
+	},
+	FreeUnalloced {
+		pointer: u32,
+		size: u32,
+		align: u32
+	},
+	FreeAgain {
+		pointer: u32,
+		size: u32,
+		align: u32
+	},
+	FreeMismatchedSize {
+		pointer: u32,
+		tried_size: u32,
+		tried_align: u32,
+		actual_size: u32
+	},
+	ReallocFreed {
+		pointer: u32,
+		size: u32,
+		align: u32
+	},
+	ReallocUnalloced {
+		pointer: u32,
+		size: u32,
+		align: u32
+	},
+	BlockSizeMismatch {
+		pointer: u32,
+		expected_size: u32,
+		align: u32,
+		actual_size: u32
+	}
+}
+
+impl fmt::Display for AllocError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match *self {
+			Self::InvalidAlignment { align } => write!(
+				f, "alignment cannot be less than 1 (got {align})"
+			),
+			Self::AddressSpaceExhausted { size, align } => write!(
+				f,
+				"no room left in the address space for a block \
+				of size {size} with alignment {align}"
+			),
+			Self::FreeUnalloced { pointer, size, align } => write!(
+				f,
+				"tried to free unallocated memory \
+				(of size {size} with alignment {align}) at {pointer:#x}"
+			),
+			Self::FreeAgain { pointer, size, align } => write!(
+				f,
+				"tried to free again memory \
+				(of size {size} with alignment {align}) at {pointer:#x}"
+			),
+			Self::FreeMismatchedSize {
+				pointer, tried_size, tried_align, actual_size
+			} => write!(
+				f,
+				"tried to free memory with mismatched size \
+				(tried size {tried_size} with alignment {tried_align}, \
+				though the block was of size {actual_size}) at {pointer:#x}"
+			),
+			Self::ReallocFreed { pointer, size, align } => write!(
+				f,
+				"tried to reallocate freed memory \
+				(of size {size} with alignment {align}) at {pointer:#x}"
+			),
+			Self::ReallocUnalloced { pointer, size, align } => write!(
+				f,
+				"tried to reallocate unallocated memory \
+				(of size {size} with alignment {align}) at {pointer:#x}"
+			),
+			Self::BlockSizeMismatch {
+				pointer, expected_size, align, actual_size
+			} => write!(
+				f,
+				"encountered memory block size mismatch \
+				(expected size {expected_size} with alignment {align}, \
+				though the block was of size {actual_size}) at {pointer:#x}"
+			)
+		}
+	}
+}
+
+impl Error for AllocError {}
+
+/// Table of the blocks handed out to the guest, placed the same way as
+/// `AllocModule.newBlock` in `index.js` does.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlockTable {
+	base: Option<u32>,
+	blocks: Vec<Block>
+}
+
+impl BlockTable {
+	pub const fn new() -> Self {
+		Self {
+			base: None,
+			blocks: Vec::new()
+		}
+	}
+
+	/// Returns the address below which no block is placed, if known.
+	pub const fn base(&self) -> Option<u32> {
+		self.base
+	}
+
+	/// Makes blocks be placed from `base` on, such as from the guest's heap
+	/// base. Without a base, the first block is placed at its alignment.
+	pub fn set_base(&mut self, base: u32) {
+		self.base = Some(base);
+	}
+
+	pub fn blocks(&self) -> &[Block] {
+		&self.blocks
+	}
+
+	/// Returns the first block that starts at `pointer`.
+	pub fn block(&self, pointer: u32) -> Option<&Block> {
+		self.blocks.iter().find(move |block| block.pointer == pointer)
+	}
+
+	/// Returns the lowest address a block may start at.
+	fn first_pointer(&self) -> u32 {
+		// Never hand out null.
+		self.base.unwrap_or(0).max(1)
+	}
+
+	fn align_pointer(address: u32, align: u32) -> Option<u32> {
+		address.div_ceil(align).checked_mul(align)
+	}
+
+	/// Places a new block, reusing the first unused one that fits, and returns
+	/// its index.
+	fn place(&mut self, size: u32, align: u32) -> Result<usize, AllocError> {
+		if align < 1 {
+			return Err(AllocError::InvalidAlignment { align })
+		}
+
+		let exhausted = AllocError::AddressSpaceExhausted { size, align };
+
+		for i in 0..self.blocks.len() {
+			let block = &self.blocks[i];
+			if block.used || block.size < size { continue }
+
+			let start = match i.checked_sub(1) {
+				Some(prev) => self.blocks[prev].next_pointer(),
+				None => Some(self.first_pointer())
+			};
+			let Some(pointer) = start
+				.and_then(move |start| Self::align_pointer(start, align))
+			else {
+				continue
+			};
+
+			if let Some(next_block) = self.blocks.get(i + 1) {
+				let fits = pointer.checked_add(size)
+					.is_some_and(|end| end <= next_block.pointer);
+				if !fits { continue }
+			}
+
+			let block = &mut self.blocks[i];
+			block.pointer = pointer;
+			block.used = true;
+			block.size = size;
+			return Ok(i)
+		}
+
+		let pointer = match self.blocks.last() {
+			Some(last_block) => last_block.next_pointer()
+				.and_then(move |next| Self::align_pointer(next, align))
+				.ok_or(exhausted)?,
+			None => Self::align_pointer(self.first_pointer(), align)
+				.ok_or(exhausted)?
+		};
+		pointer.checked_add(size).ok_or(exhausted)?;
+
+		self.blocks.push(Block::new(pointer, size));
+		Ok(self.blocks.len() - 1)
+	}
+
+	/// Places a new block, reusing the first unused one that fits.
+	pub fn alloc(&mut self, size: u32, align: u32) -> Result<u32, AllocError> {
+		let index = self.place(size, align)?;
+		Ok(self.blocks[index].pointer)
+	}
+
+	/// Like [`alloc`](Self::alloc), but gives up on the block if `fits`
+	/// rejects the address it ends at, as `AllocModule.newBlockInMemory` in
+	/// `index.js` does when memory cannot be grown to fit it.
+	pub fn alloc_in(
+		&mut self,
+		size: u32, align: u32,
+		fits: impl FnOnce(usize) -> bool
+	) -> Result<Option<u32>, AllocError> {
+		let index = match self.place(size, align) {
+			Ok(index) => index,
+			Err(AllocError::AddressSpaceExhausted { .. }) => return Ok(None),
+			Err(error) => return Err(error)
+		};
+
+		let block = &mut self.blocks[index];
+		if !fits(block.pointer as usize + block.size as usize) {
+			block.used = false;
+			return Ok(None)
+		}
+		Ok(Some(block.pointer))
+	}
+
+	pub fn dealloc(
+		&mut self,
+		pointer: u32, size: u32, align: u32
+	) -> Result<(), AllocError> {
+		let Some(block) = self.blocks.iter_mut()
+			.find(move |block| block.pointer == pointer)
+		else {
+			return Err(AllocError::FreeUnalloced { pointer, size, align })
+		};
+
+		if !block.used {
+			return Err(AllocError::FreeAgain { pointer, size, align })
+		}
+
+		if block.size != size {
+			return Err(AllocError::FreeMismatchedSize {
+				pointer,
+				tried_size: size,
+				tried_align: align,
+				actual_size: block.size
+			})
+		}
+
+		block.used = false;
+		Ok(())
+	}
+
+	/// Moves a block to a new place that fits `new_size`, or keeps it where it
+	/// is and returns `None` if `fits` rejects the new place.
+	///
+	/// The caller is responsible for copying the contents of the block from
+	/// `pointer` to the returned address, which may overlap it.
+	pub fn realloc(
+		&mut self,
+		pointer: u32, size: u32, align: u32,
+		new_size: u32,
+		fits: impl FnOnce(usize) -> bool
+	) -> Result<Option<u32>, AllocError> {
+		if size == new_size { return Ok(Some(pointer)) }
+
+		let Some(index) = self.blocks.iter()
+			.position(move |block| block.pointer == pointer)
+		else {
+			return Err(AllocError::ReallocUnalloced { pointer, size, align })
+		};
+
+		let block = &mut self.blocks[index];
+		if !block.used {
+			return Err(AllocError::ReallocFreed { pointer, size, align })
+		}
+
+		if block.size != size {
+			return Err(AllocError::BlockSizeMismatch {
+				pointer,
+				expected_size: size,
+				align,
+				actual_size: block.size
+			})
+		}
+
+		block.used = false;
+		let result = self.alloc_in(new_size, align, fits);
+		if !matches!(result, Ok(Some(_))) {
+			self.blocks[index].used = true;
+		}
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn first_block_starts_at_alignment() {
+		let mut table = BlockTable::new();
+		assert_eq!(table.alloc(3, 8), Ok(8));
+		assert_eq!(table.alloc(4, 4), Ok(12));
+		assert_eq!(table.alloc(1, 16), Ok(16));
+	}
+
+	#[test]
+	fn first_block_starts_past_base() {
+		let mut table = BlockTable::new();
+		table.set_base(0x1234);
+		assert_eq!(table.alloc(4, 16), Ok(0x1240));
+	}
+
+	#[test]
+	fn freed_blocks_are_reused_when_they_fit() {
+		let mut table = BlockTable::new();
+		let a = table.alloc(16, 8).unwrap();
+		let b = table.alloc(16, 8).unwrap();
+		let c = table.alloc(16, 8).unwrap();
+		table.dealloc(b, 16, 8).unwrap();
+
+		assert_eq!(table.alloc(32, 8), Ok(c + 16));
+		assert_eq!(table.alloc(8, 8), Ok(a + 16));
+		assert_eq!(table.block(b), Some(&Block::new(b, 8)));
+	}
+
+	#[test]
+	fn reused_first_block_is_aligned() {
+		let mut table = BlockTable::new();
+		table.set_base(1028);
+		let a = table.alloc(8, 4).unwrap();
+		let b = table.alloc(8, 4).unwrap();
+		assert_eq!((a, b), (1028, 1036));
+		table.dealloc(a, 8, 4).unwrap();
+
+		// The first block cannot be aligned to 16 before the second one.
+		assert_eq!(table.alloc(8, 16), Ok(1056));
+		assert!(!table.block(a).unwrap().used);
+		assert_eq!(table.alloc(4, 4), Ok(1028));
+	}
+
+	#[test]
+	fn misuse_is_reported() {
+		let mut table = BlockTable::new();
+		let pointer = table.alloc(16, 8).unwrap();
+
+		assert_eq!(
+			table.dealloc(pointer, 8, 8),
+			Err(AllocError::FreeMismatchedSize {
+				pointer,
+				tried_size: 8,
+				tried_align: 8,
+				actual_size: 16
+			})
+		);
+		assert_eq!(
+			table.dealloc(pointer + 1, 16, 8),
+			Err(AllocError::FreeUnalloced { pointer: pointer + 1, size: 16, align: 8 })
+		);
+
+		table.dealloc(pointer, 16, 8).unwrap();
+		assert_eq!(
+			table.dealloc(pointer, 16, 8),
+			Err(AllocError::FreeAgain { pointer, size: 16, align: 8 })
+		);
+		assert_eq!(
+			table.realloc(pointer, 16, 8, 32, |_| true),
+			Err(AllocError::ReallocFreed { pointer, size: 16, align: 8 })
+		);
+		assert_eq!(table.alloc(1, 0), Err(AllocError::InvalidAlignment { align: 0 }));
+	}
+
+	#[test]
+	fn blocks_that_do_not_fit_are_given_up() {
+		let mut table = BlockTable::new();
+		let fits = |end| end <= 64;
+		let a = table.alloc_in(16, 8, fits).unwrap().unwrap();
+		assert_eq!(table.alloc_in(64, 8, fits), Ok(None));
+		assert_eq!(table.realloc(a, 16, 8, 64, fits), Ok(None));
+		assert!(table.block(a).unwrap().used);
+	}
+
+	#[test]
+	fn realloc_moves_the_block() {
+		let mut table = BlockTable::new();
+		let a = table.alloc(16, 8).unwrap();
+		table.alloc(16, 8).unwrap();
+
+		let b = table.realloc(a, 16, 8, 64, |_| true).unwrap().unwrap();
+		assert_ne!(a, b);
+		assert_eq!(table.block(b), Some(&Block::new(b, 64)));
+		assert_eq!(
+			table.realloc(a, 32, 8, 64, |_| true),
+			Err(AllocError::ReallocFreed { pointer: a, size: 32, align: 8 })
+		);
+	}
+}
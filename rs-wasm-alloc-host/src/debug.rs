@@ -0,0 +1,99 @@
+//! Host side of the `debug` import module.
+
+use std::collections::BTreeMap;
+
+/// Record of the `log` crate, logged by the guest through `dblog_record`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugRecord {
+	/// Level as in `log::Level`, from 1 for errors to 5 for traces.
+	pub level: u32,
+	pub target: String,
+	pub module_path: Option<String>,
+	pub file: Option<String>,
+	pub line: Option<u32>,
+	pub message: String
+}
+
+/// Debug log of the guest, accumulated until it is flushed.
+#[derive(Debug, Clone)]
+pub struct DebugState {
+	pub pending: String,
+	/// Receives every flushed message. Defaults to printing to `stderr`.
+	pub sink: fn(&str),
+	/// Receives every record. Defaults to printing to `stderr`.
+	pub record_sink: fn(&DebugRecord),
+	/// Pending output of every stream written with `dblog_write`.
+	pub streams: BTreeMap<u32, String>,
+	/// Receives every line flushed to a stream, 1 being `stdout` and 2
+	/// `stderr`. Defaults to printing to the host's stream of the same number.
+	pub stream_sink: fn(u32, &str)
+}
+
+impl Default for DebugState {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl DebugState {
+	pub const fn new() -> Self {
+		Self {
+			pending: String::new(),
+			sink: print_to_stderr,
+			record_sink: print_record_to_stderr,
+			streams: BTreeMap::new(),
+			stream_sink: print_to_stream
+		}
+	}
+
+	pub fn flush(&mut self) {
+		(self.sink)(&self.pending);
+		self.pending.clear();
+	}
+
+	pub fn write(&mut self, stream: u32, str: &str) {
+		self.streams.entry(stream).or_default().push_str(str);
+	}
+
+	pub fn flush_stream(&mut self, stream: u32) {
+		let line = self.streams.remove(&stream).unwrap_or_default();
+		(self.stream_sink)(stream, &line);
+	}
+
+	/// Finishes a record with the pending message.
+	pub fn record(
+		&mut self,
+		level: u32,
+		target: String,
+		module_path: Option<String>,
+		file: Option<String>,
+		line: Option<u32>
+	) {
+		let record = DebugRecord {
+			level,
+			target,
+			module_path,
+			file,
+			line,
+			message: std::mem::take(&mut self.pending)
+		};
+		(self.record_sink)(&record);
+	}
+}
+
+fn print_to_stderr(message: &str) {
+	eprintln!("{message}");
+}
+
+fn print_to_stream(stream: u32, line: &str) {
+	match stream {
+		2 => eprintln!("{line}"),
+		_ => println!("{line}")
+	}
+}
+
+fn print_record_to_stderr(record: &DebugRecord) {
+	const LEVELS: [&str; 6] = ["?", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+	let level = LEVELS.get(record.level as usize).unwrap_or(&LEVELS[0]);
+	eprintln!("[{level} {}] {}", record.target, record.message);
+}
@@ -0,0 +1,49 @@
+//! Host side of the `rs-wasm-alloc` import modules, for native embedders.
+
+mod alloc;
+pub use alloc::*;
+
+mod debug;
+pub use debug::*;
+
+mod panic;
+pub use panic::*;
+
+#[cfg(feature = "wasmi")]
+pub mod linker;
+
+/// Version of the import modules implemented by this host, as in the guest's
+/// `abi::VERSION`.
+pub const ABI_VERSION: u32 = 1;
+
+/// State of every import module for a single guest instance.
+#[derive(Debug, Clone)]
+pub struct Host {
+	pub blocks: BlockTable,
+	/// Receives the layouts of the blocks the guest failed to allocate.
+	/// Defaults to printing to `stderr`.
+	pub on_oom: fn(size: u32, align: u32),
+	pub panic: PanicState,
+	pub debug: DebugState
+}
+
+impl Default for Host {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Host {
+	pub const fn new() -> Self {
+		Self {
+			blocks: BlockTable::new(),
+			on_oom: print_oom,
+			panic: PanicState::new(),
+			debug: DebugState::new()
+		}
+	}
+}
+
+fn print_oom(size: u32, align: u32) {
+	eprintln!("out of memory for a block of size {size} with alignment {align}");
+}
@@ -0,0 +1,571 @@
+//! Registration of the import modules on a [`wasmi::Linker`].
+
+use std::mem;
+use std::ops::Range;
+
+use wasmi::errors::{
+	HostError,
+	LinkerError
+};
+use wasmi::{
+	AsContextMut,
+	Caller,
+	Error,
+	Extern,
+	Instance,
+	Linker,
+	Memory
+};
+
+use crate::{
+	ABI_VERSION,
+	AbortReason,
+	AllocError,
+	BlockTable,
+	Host,
+	PanicState,
+	PayloadKind,
+	WasmPanic
+};
+
+/// Size of a WebAssembly page, in bytes.
+pub const PAGE_SIZE: usize = 65536;
+
+impl HostError for AllocError {}
+impl HostError for WasmPanic {}
+
+fn memory<T>(caller: &Caller<'_, T>) -> Result<Memory, Error> {
+	caller.get_export("memory")
+		.and_then(Extern::into_memory)
+		.ok_or_else(|| Error::new("memory is not exported"))
+}
+
+fn range(pointer: i32, length: i32) -> Range<usize> {
+	let start = pointer as u32 as usize;
+	start..start.saturating_add(length as u32 as usize)
+}
+
+fn out_of_bounds(range: &Range<usize>) -> Error {
+	Error::new(format!(
+		"{:#x}..{:#x} is out of bounds of memory", range.start, range.end
+	))
+}
+
+fn read_str<T>(
+	caller: &Caller<'_, T>,
+	pointer: i32, length: i32
+) -> Result<String, Error> {
+	let range = range(pointer, length);
+	let bytes = memory(caller)?.data(caller)
+		.get(range.clone())
+		.ok_or_else(|| out_of_bounds(&range))?;
+	Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Grows `memory` to extend at least to `end`, through the guest's
+/// `__rs_wasm_alloc_grow` export, or directly if the guest does not export it.
+fn grow_to<T>(
+	caller: &mut Caller<'_, T>,
+	memory: Memory,
+	end: usize
+) -> Result<bool, Error> {
+	let memory_size = memory.data(&*caller).len();
+	if end <= memory_size { return Ok(true) }
+	let pages = (end - memory_size).div_ceil(PAGE_SIZE);
+
+	match caller.get_export("__rs_wasm_alloc_grow").and_then(Extern::into_func) {
+		Some(grow) => {
+			let grow = grow.typed::<i32, i32>(&*caller)?;
+			Ok(grow.call(&mut *caller, pages as i32)? != -1)
+		}
+		None => Ok(memory.grow(caller, pages as u64).is_ok())
+	}
+}
+
+/// Returns the address where the guest's heap may begin, through the guest's
+/// `__rs_wasm_alloc_heap_base` export or its `__heap_base` global, or 0 if the
+/// guest does not tell.
+fn heap_base<T>(caller: &mut Caller<'_, T>) -> Result<u32, Error> {
+	if let Some(heap_base) = caller.get_export("__rs_wasm_alloc_heap_base")
+		.and_then(Extern::into_func)
+	{
+		let heap_base = heap_base.typed::<(), i32>(&*caller)?;
+		return Ok(heap_base.call(&mut *caller, ())? as u32)
+	}
+
+	let heap_base = caller.get_export("__heap_base")
+		.and_then(Extern::into_global)
+		.and_then(|global| global.get(&*caller).i32());
+	Ok(heap_base.unwrap_or(0) as u32)
+}
+
+/// Runs `f` on the block table of the guest, letting it check whether a block
+/// fits in memory, growing memory as needed.
+///
+/// If the table has no base yet, it is set to the guest's heap base first.
+fn with_blocks<T, R>(
+	caller: &mut Caller<'_, T>,
+	memory: Memory,
+	get: fn(&mut T) -> &mut Host,
+	f: impl FnOnce(&mut BlockTable, &mut dyn FnMut(usize) -> bool) -> Result<R, AllocError>
+) -> Result<R, Error> {
+	// The table is taken out of the store, so that the guest can be called back
+	// while it is in use.
+	let mut blocks = mem::take(&mut get(caller.data_mut()).blocks);
+	if blocks.base().is_none() {
+		match heap_base(caller) {
+			Ok(base) => blocks.set_base(base),
+			Err(error) => {
+				get(caller.data_mut()).blocks = blocks;
+				return Err(error)
+			}
+		}
+	}
+
+	let mut grow_error = None;
+	let result = f(&mut blocks, &mut |end| {
+		grow_to(caller, memory, end).unwrap_or_else(|error| {
+			grow_error = Some(error);
+			false
+		})
+	});
+	get(caller.data_mut()).blocks = blocks;
+
+	match grow_error {
+		Some(error) => Err(error),
+		None => result.map_err(Error::host)
+	}
+}
+
+fn decode_char(code: i32) -> char {
+	char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+/// Checks the `__rs_wasm_alloc_abi_version` export of the guest against
+/// [`ABI_VERSION`], returning its `__rs_wasm_alloc_capabilities`, or `None` if
+/// the guest exports no version.
+pub fn check_guest_abi(
+	mut store: impl AsContextMut,
+	instance: &Instance
+) -> Result<Option<u32>, Error> {
+	let Ok(version) = instance
+		.get_typed_func::<(), u32>(&store, "__rs_wasm_alloc_abi_version")
+	else {
+		return Ok(None)
+	};
+	let version = version.call(&mut store, ())?;
+	if version != ABI_VERSION {
+		return Err(Error::new(format!(
+			"guest was built for version {version} of the rs-wasm-alloc ABI, \
+			but the host implements version {ABI_VERSION}"
+		)))
+	}
+
+	match instance.get_typed_func::<(), u32>(&store, "__rs_wasm_alloc_capabilities") {
+		Ok(capabilities) => Ok(Some(capabilities.call(&mut store, ())?)),
+		Err(_) => Ok(Some(0))
+	}
+}
+
+/// Defines the `alloc`, `panic` and `debug` import modules on `linker`.
+///
+/// `get` projects the [`Host`] state of a guest instance out of the data of
+/// its store.
+pub fn add_to_linker<T: 'static>(
+	linker: &mut Linker<T>,
+	get: fn(&mut T) -> &mut Host
+) -> Result<(), LinkerError> {
+	linker.func_wrap(
+		"alloc", "alloc",
+		move |mut caller: Caller<'_, T>, size: i32, align: i32| {
+			let memory = memory(&caller)?;
+			let pointer = with_blocks(&mut caller, memory, get, |blocks, fits| {
+				blocks.alloc_in(size as u32, align as u32, fits)
+			})?;
+			Ok(pointer.unwrap_or(0) as i32)
+		}
+	)?;
+
+	linker.func_wrap(
+		"alloc", "alloc_zeroed",
+		move |mut caller: Caller<'_, T>, size: i32, align: i32| {
+			let memory = memory(&caller)?;
+			let pointer = with_blocks(&mut caller, memory, get, |blocks, fits| {
+				blocks.alloc_in(size as u32, align as u32, fits)
+			})?;
+			let Some(pointer) = pointer else { return Ok(0) };
+
+			memory.data_mut(&mut caller)[range(pointer as i32, size)].fill(0);
+			Ok(pointer as i32)
+		}
+	)?;
+
+	linker.func_wrap(
+		"alloc", "dealloc",
+		move |mut caller: Caller<'_, T>, pointer: i32, size: i32, align: i32| {
+			let blocks = &mut get(caller.data_mut()).blocks;
+			blocks.dealloc(pointer as u32, size as u32, align as u32)
+				.map_err(Error::host)
+		}
+	)?;
+
+	linker.func_wrap(
+		"alloc", "realloc",
+		move |
+			mut caller: Caller<'_, T>,
+			pointer: i32, size: i32, align: i32,
+			new_size: i32
+		| {
+			let memory = memory(&caller)?;
+			let new_pointer = with_blocks(&mut caller, memory, get, |blocks, fits| {
+				blocks.realloc(
+					pointer as u32, size as u32, align as u32,
+					new_size as u32,
+					fits
+				)
+			})?;
+			let Some(new_pointer) = new_pointer else { return Ok(0) };
+			let new_pointer = new_pointer as i32;
+
+			if new_pointer != pointer {
+				let source = range(pointer, size.min(new_size));
+				let data = memory.data_mut(&mut caller);
+				if data.len() < source.end {
+					return Err(out_of_bounds(&source))
+				}
+				data.copy_within(source, new_pointer as u32 as usize);
+			}
+			Ok(new_pointer)
+		}
+	)?;
+
+	linker.func_wrap(
+		"alloc", "oom",
+		move |mut caller: Caller<'_, T>, size: i32, align: i32| {
+			(get(caller.data_mut()).on_oom)(size as u32, align as u32);
+		}
+	)?;
+
+	linker.func_wrap("alloc", "abi_version", || ABI_VERSION)?;
+
+	linker.func_wrap(
+		"panic", "panic",
+		move |mut caller: Caller<'_, T>| -> Result<(), Error> {
+			let panic = get(caller.data_mut()).panic.take();
+			Err(Error::host(panic))
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_put_file",
+		move |mut caller: Caller<'_, T>, pointer: i32, length: i32| {
+			let file = read_str(&caller, pointer, length)?;
+			get(caller.data_mut()).panic.file = Some(file);
+			Ok(())
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_put_line_column",
+		move |mut caller: Caller<'_, T>, line: i32, column: i32| {
+			let panic = &mut get(caller.data_mut()).panic;
+			panic.line = line as u32;
+			panic.column = column as u32;
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_put_payload_kind",
+		move |mut caller: Caller<'_, T>, kind: i32| {
+			get(caller.data_mut()).panic.payload_kind = PayloadKind::from(kind as u32);
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_put_abort_reason",
+		move |mut caller: Caller<'_, T>, reason: i32| {
+			get(caller.data_mut()).panic.abort_reason = AbortReason::from(reason as u32);
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_ch",
+		move |mut caller: Caller<'_, T>, code: i32| {
+			get(caller.data_mut()).panic.message.push(decode_char(code));
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_str",
+		move |mut caller: Caller<'_, T>, pointer: i32, length: i32| {
+			let str = read_str(&caller, pointer, length)?;
+			get(caller.data_mut()).panic.message.push_str(&str);
+			Ok(())
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_nested",
+		move |caller: Caller<'_, T>, pointer: i32, length: i32, line: i32|
+			-> Result<(), Error>
+		{
+			Err(Error::host(WasmPanic {
+				message: "panicked while panicking".into(),
+				file: Some(read_str(&caller, pointer, length)?),
+				line: line as u32,
+				column: 0,
+				payload_kind: PayloadKind::Unknown(0),
+				abort_reason: AbortReason::Unknown(0)
+			}))
+		}
+	)?;
+
+	linker.func_wrap(
+		"panic", "panic_report",
+		move |caller: Caller<'_, T>, pointer: i32| -> Result<(), Error> {
+			let range = range(pointer, 32);
+			let bytes = memory(&caller)?.data(&caller)
+				.get(range.clone())
+				.ok_or_else(|| out_of_bounds(&range))?;
+			let mut fields = [0; 8];
+			for (field, bytes) in fields.iter_mut().zip(bytes.chunks_exact(4)) {
+				*field = i32::from_le_bytes(bytes.try_into().unwrap());
+			}
+			let [
+				message, message_len,
+				file, file_len,
+				line, column,
+				payload_kind, abort_reason
+			] = fields;
+
+			let mut panic = PanicState {
+				message: read_str(&caller, message, message_len)?,
+				file: (file != 0)
+					.then(|| read_str(&caller, file, file_len))
+					.transpose()?,
+				line: line as u32,
+				column: column as u32,
+				payload_kind: PayloadKind::from(payload_kind as u32),
+				abort_reason: AbortReason::from(abort_reason as u32)
+			};
+			Err(Error::host(panic.take()))
+		}
+	)?;
+
+	linker.func_wrap(
+		"debug", "dblog_ch",
+		move |mut caller: Caller<'_, T>, code: i32| {
+			get(caller.data_mut()).debug.pending.push(decode_char(code));
+		}
+	)?;
+
+	linker.func_wrap(
+		"debug", "dblog_str",
+		move |mut caller: Caller<'_, T>, pointer: i32, length: i32| {
+			let str = read_str(&caller, pointer, length)?;
+			get(caller.data_mut()).debug.pending.push_str(&str);
+			Ok(())
+		}
+	)?;
+
+	linker.func_wrap(
+		"debug", "dblog_flush",
+		move |mut caller: Caller<'_, T>| {
+			get(caller.data_mut()).debug.flush();
+		}
+	)?;
+
+	linker.func_wrap(
+		"debug", "dblog_write",
+		move |mut caller: Caller<'_, T>, stream: i32, pointer: i32, length: i32| {
+			let str = read_str(&caller, pointer, length)?;
+			get(caller.data_mut()).debug.write(stream as u32, &str);
+			Ok(())
+		}
+	)?;
+
+	linker.func_wrap(
+		"debug", "dblog_flush_stream",
+		move |mut caller: Caller<'_, T>, stream: i32| {
+			get(caller.data_mut()).debug.flush_stream(stream as u32);
+		}
+	)?;
+
+	linker.func_wrap(
+		"debug", "dblog_record",
+		move |
+			mut caller: Caller<'_, T>,
+			level: i32,
+			target: i32, target_len: i32,
+			module_path: i32, module_path_len: i32,
+			file: i32, file_len: i32,
+			line: i32
+		| {
+			let target = read_str(&caller, target, target_len)?;
+			let module_path = (module_path != 0)
+				.then(|| read_str(&caller, module_path, module_path_len))
+				.transpose()?;
+			let file = (file != 0)
+				.then(|| read_str(&caller, file, file_len))
+				.transpose()?;
+			let line = (line != 0).then_some(line as u32);
+			get(caller.data_mut()).debug.record(
+				level as u32, target, module_path, file, line
+			);
+			Ok(())
+		}
+	)?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use wasmi::{
+		Engine,
+		Module,
+		Store
+	};
+
+	use super::*;
+
+	const GUEST: &str = r#"
+		(module
+			(import "alloc" "alloc" (func $alloc (param i32 i32) (result i32)))
+			(import "alloc" "realloc"
+				(func $realloc (param i32 i32 i32 i32) (result i32)))
+			(import "alloc" "dealloc" (func $dealloc (param i32 i32 i32)))
+			(import "alloc" "abi_version" (func $abi_version (result i32)))
+			(import "panic" "panic_str" (func $panic_str (param i32 i32)))
+			(import "panic" "panic" (func $panic))
+			(import "panic" "panic_report" (func $panic_report (param i32)))
+			(memory (export "memory") 1 2)
+			(global (export "__heap_base") i32 (i32.const 2048))
+			(data (i32.const 1024) "oh no")
+			(data (i32.const 1040) "src/lib.rs")
+			(data (i32.const 1056)
+				"\00\04\00\00" "\05\00\00\00"
+				"\10\04\00\00" "\0a\00\00\00"
+				"\07\00\00\00" "\03\00\00\00"
+				"\01\00\00\00" "\05\00\00\00")
+
+			(func (export "grow_twice") (result i32)
+				(local $ptr i32)
+				(local.set $ptr (call $alloc (i32.const 4) (i32.const 4)))
+				(i32.store (local.get $ptr) (i32.const 0x12345678))
+				(local.set $ptr
+					(call $realloc (local.get $ptr) (i32.const 4) (i32.const 4)
+						(i32.const 8)))
+				(drop (call $alloc (i32.const 4) (i32.const 4)))
+				(i32.load (local.get $ptr)))
+
+			(func (export "alloc_page") (result i32)
+				(call $alloc (i32.const 0x10000) (i32.const 8)))
+
+			(func (export "alloc_too_much") (result i32)
+				(call $alloc (i32.const 0x20000) (i32.const 8)))
+
+			(func (export "free_twice")
+				(local $ptr i32)
+				(local.set $ptr (call $alloc (i32.const 4) (i32.const 4)))
+				(call $dealloc (local.get $ptr) (i32.const 4) (i32.const 4))
+				(call $dealloc (local.get $ptr) (i32.const 4) (i32.const 4)))
+
+			(func (export "explode")
+				(call $panic_str (i32.const 1024) (i32.const 5))
+				(call $panic))
+
+			(func (export "__rs_wasm_alloc_abi_version") (result i32)
+				(call $abi_version))
+
+			(func (export "__rs_wasm_alloc_capabilities") (result i32)
+				(i32.const 5))
+
+			(func (export "report")
+				(call $panic_report (i32.const 1056)))
+		)
+	"#;
+
+	fn instantiate() -> (Store<Host>, wasmi::Instance) {
+		let engine = Engine::default();
+		let module = Module::new(&engine, GUEST).unwrap();
+		let mut linker = Linker::new(&engine);
+		add_to_linker(&mut linker, |host| host).unwrap();
+
+		let mut store = Store::new(&engine, Host::new());
+		let instance = linker.instantiate_and_start(&mut store, &module).unwrap();
+		(store, instance)
+	}
+
+	#[test]
+	fn realloc_copies_contents() {
+		let (mut store, instance) = instantiate();
+		let grow_twice = instance
+			.get_typed_func::<(), i32>(&store, "grow_twice").unwrap();
+		assert_eq!(grow_twice.call(&mut store, ()).unwrap(), 0x12345678);
+	}
+
+	#[test]
+	fn memory_is_grown_to_fit() {
+		let (mut store, instance) = instantiate();
+		let alloc_page = instance
+			.get_typed_func::<(), i32>(&store, "alloc_page").unwrap();
+		assert_eq!(alloc_page.call(&mut store, ()).unwrap(), 2048);
+		let memory = instance.get_memory(&store, "memory").unwrap();
+		assert_eq!(memory.size(&store), 2);
+	}
+
+	#[test]
+	fn exhaustion_returns_null() {
+		let (mut store, instance) = instantiate();
+		let alloc_too_much = instance
+			.get_typed_func::<(), i32>(&store, "alloc_too_much").unwrap();
+		assert_eq!(alloc_too_much.call(&mut store, ()).unwrap(), 0);
+	}
+
+	#[test]
+	fn misuse_traps_with_alloc_error() {
+		let (mut store, instance) = instantiate();
+		let free_twice = instance
+			.get_typed_func::<(), ()>(&store, "free_twice").unwrap();
+		let error = free_twice.call(&mut store, ()).unwrap_err();
+		assert_eq!(
+			error.downcast_ref::<AllocError>(),
+			Some(&AllocError::FreeAgain { pointer: 2048, size: 4, align: 4 })
+		);
+	}
+
+	#[test]
+	fn panic_traps_with_message() {
+		let (mut store, instance) = instantiate();
+		let explode = instance
+			.get_typed_func::<(), ()>(&store, "explode").unwrap();
+		let error = explode.call(&mut store, ()).unwrap_err();
+		assert_eq!(
+			error.downcast_ref::<WasmPanic>().map(|panic| &*panic.message),
+			Some("oh no")
+		);
+	}
+
+	#[test]
+	fn panic_report_traps_in_one_call() {
+		let (mut store, instance) = instantiate();
+		let report = instance
+			.get_typed_func::<(), ()>(&store, "report").unwrap();
+		let error = report.call(&mut store, ()).unwrap_err();
+		assert_eq!(error.downcast_ref::<WasmPanic>(), Some(&WasmPanic {
+			message: "oh no".into(),
+			file: Some("src/lib.rs".into()),
+			line: 7,
+			column: 3,
+			payload_kind: PayloadKind::Literal,
+			abort_reason: AbortReason::Assertion
+		}));
+	}
+
+	#[test]
+	fn guest_abi_is_checked() {
+		let (mut store, instance) = instantiate();
+		assert_eq!(check_guest_abi(&mut store, &instance).unwrap(), Some(5));
+	}
+}
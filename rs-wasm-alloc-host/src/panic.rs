@@ -0,0 +1,168 @@
+//! Host side of the `panic` import module.
+
+use std::error::Error;
+use std::fmt;
+
+/// How the message of a guest panic was built, as in the guest's
+/// `PayloadKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PayloadKind {
+	/// String literal, as with `panic!("oh no")`.
+	Literal,
+	/// Formatted message, as with `panic!("oh {}", "no")`.
+	Formatted,
+	/// `String`, as passed to `std::panic::panic_any`.
+	String,
+	/// Kind not reported by the guest (0), or unknown to this host.
+	Unknown(u32)
+}
+
+impl Default for PayloadKind {
+	fn default() -> Self {
+		Self::Unknown(0)
+	}
+}
+
+impl From<u32> for PayloadKind {
+	fn from(code: u32) -> Self {
+		match code {
+			1 => Self::Literal,
+			2 => Self::Formatted,
+			3 => Self::String,
+			code => Self::Unknown(code)
+		}
+	}
+}
+
+impl From<PayloadKind> for u32 {
+	fn from(kind: PayloadKind) -> Self {
+		match kind {
+			PayloadKind::Literal => 1,
+			PayloadKind::Formatted => 2,
+			PayloadKind::String => 3,
+			PayloadKind::Unknown(code) => code
+		}
+	}
+}
+
+/// Why the guest aborted, as in the guest's `AbortReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AbortReason {
+	/// Panic of the guest's own code.
+	Panic,
+	/// Failed allocation, passed to `handle_alloc_error`.
+	AllocFailure,
+	/// Host that does not support the guest's ABI.
+	AbiMismatch,
+	/// Inconsistent state of an allocator in the guest.
+	CorruptedState,
+	/// Failed `assert!`, `assert_eq!` or `assert_ne!`.
+	Assertion,
+	/// Reason not reported by the guest (0), or unknown to this host.
+	Unknown(u32)
+}
+
+impl Default for AbortReason {
+	fn default() -> Self {
+		Self::Unknown(0)
+	}
+}
+
+impl From<u32> for AbortReason {
+	fn from(code: u32) -> Self {
+		match code {
+			1 => Self::Panic,
+			2 => Self::AllocFailure,
+			3 => Self::AbiMismatch,
+			4 => Self::CorruptedState,
+			5 => Self::Assertion,
+			code => Self::Unknown(code)
+		}
+	}
+}
+
+impl From<AbortReason> for u32 {
+	fn from(reason: AbortReason) -> Self {
+		match reason {
+			AbortReason::Panic => 1,
+			AbortReason::AllocFailure => 2,
+			AbortReason::AbiMismatch => 3,
+			AbortReason::CorruptedState => 4,
+			AbortReason::Assertion => 5,
+			AbortReason::Unknown(code) => code
+		}
+	}
+}
+
+/// Panic reported by the guest, mirroring `WasmPanicError` in `index.js`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasmPanic {
+	pub message: String,
+	pub file: Option<String>,
+	pub line: u32,
+	pub column: u32,
+	pub payload_kind: PayloadKind,
+	pub abort_reason: AbortReason
+}
+
+impl fmt::Display for WasmPanic {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+impl Error for WasmPanic {}
+
+/// Panic being reported by the guest, accumulated over several imports.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PanicState {
+	pub message: String,
+	pub file: Option<String>,
+	pub line: u32,
+	pub column: u32,
+	pub payload_kind: PayloadKind,
+	pub abort_reason: AbortReason
+}
+
+impl PanicState {
+	pub const fn new() -> Self {
+		Self {
+			message: String::new(),
+			file: None,
+			line: 0,
+			column: 0,
+			payload_kind: PayloadKind::Unknown(0),
+			abort_reason: AbortReason::Unknown(0)
+		}
+	}
+
+	/// Finishes the pending panic, leaving this state empty.
+	pub fn take(&mut self) -> WasmPanic {
+		let Self {
+			message,
+			file,
+			line,
+			column,
+			payload_kind,
+			abort_reason
+		} = std::mem::take(self);
+
+		let message = if message.is_empty() {
+			format!(
+				"<no message, {}:{line}:{column}>",
+				file.as_deref().unwrap_or("?")
+			)
+		} else {
+			message
+		};
+
+		WasmPanic {
+			message,
+			file,
+			line,
+			column,
+			payload_kind,
+			abort_reason
+		}
+	}
+}
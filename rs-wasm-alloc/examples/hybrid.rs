@@ -0,0 +1,33 @@
+//! Demo guest module for `index.js` with `HybridAllocator` as the global
+//! allocator, so that small blocks never reach the host.
+//!
+//! Only `no_std` on `wasm32`, so that `cargo test` can still build it natively
+//! with the panic runtime of `std`.
+
+#![cfg_attr(target_arch = "wasm32", no_std)]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use rs_wasm_alloc::DebugLog;
+
+#[cfg(not(feature = "global-allocator"))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: rs_wasm_alloc::HybridAllocator =
+	rs_wasm_alloc::HybridAllocator::new(rs_wasm_alloc::hybrid::DEFAULT_THRESHOLD);
+
+#[export_name = "run"]
+pub extern "C" fn run() {
+	// Small strings come from slabs, the large vector from the host.
+	let names: Vec<String> = (0..100)
+		.map(|i| alloc::format!("user {i}"))
+		.collect();
+	let large = alloc::vec![0u8; 4096];
+
+	let mut log = DebugLog::new();
+	let _ = writeln!(log, "{} names, {} bytes", names.len(), large.len());
+	log.flush();
+}
@@ -0,0 +1,55 @@
+//! Demo guest module for `index.js`.
+//!
+//! Only `no_std` on `wasm32`, so that `cargo test` can still build it natively
+//! with the panic runtime of `std`.
+
+#![cfg_attr(target_arch = "wasm32", no_std)]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use rs_wasm_alloc::DebugLog;
+
+#[cfg(not(feature = "global-allocator"))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: rs_wasm_alloc::ExternAllocator =
+	rs_wasm_alloc::ExternAllocator;
+
+#[export_name = "run"]
+pub extern "C" fn run() {
+	demo();
+
+	// Everything allocated by the demo should have been freed by now.
+	#[cfg(feature = "leak-tracking")]
+	rs_wasm_alloc::leaks::dump();
+}
+
+fn demo() {
+	#[allow(dead_code)]
+	#[derive(Debug)]
+	struct User {
+		id: usize,
+		name: String
+	}
+
+	let _: String = "taketwo".into();
+
+	let mut stuff = Vec::new();
+	for i in 0..100 {
+		stuff.push(i);
+	}
+
+	let user = User {
+		id: 1337,
+		name: "track one".into()
+	};
+
+	let mut user_info = String::new();
+	user_info.push_str(&user.name);
+	let mut log = DebugLog::new();
+	let _ = writeln!(log, "{}", user_info);
+	log.flush();
+}
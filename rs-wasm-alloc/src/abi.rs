@@ -0,0 +1,117 @@
+//! Versioning of the import modules, and capabilities of the guest.
+//!
+//! Both are exported to the host as `__rs_wasm_alloc_abi_version` and
+//! `__rs_wasm_alloc_capabilities`. With the `abi-check` feature, the guest
+//! also checks the version of the host through `alloc.abi_version`.
+
+/// Version of the import modules, raised on every incompatible change to their
+/// signatures or contracts.
+pub const VERSION: u32 = 1;
+
+/// `alloc.alloc_zeroed` is imported.
+pub const ALLOC_ZEROED: u32 = 1 << 0;
+/// Panics are reported to the `panic` module.
+pub const PANIC_HANDLER: u32 = 1 << 1;
+/// Panics are reported through `panic.panic_report`.
+pub const PANIC_REPORT: u32 = 1 << 2;
+/// The `debug` module is imported.
+pub const DEBUG_LOG: u32 = 1 << 3;
+/// `log` records are sent through `debug.dblog_record`.
+pub const LOG: u32 = 1 << 4;
+/// The memory layout and growth are exported.
+pub const MEMORY_EXPORTS: u32 = 1 << 5;
+/// Allocation statistics are exported.
+pub const STATS: u32 = 1 << 6;
+/// Live allocations are tracked, and their dump exported.
+pub const LEAK_TRACKING: u32 = 1 << 7;
+/// `alloc.abi_version` is imported.
+pub const ABI_CHECK: u32 = 1 << 8;
+/// Panics and the debug log are written through WASI instead of the `panic`
+/// and `debug` modules.
+pub const WASI: u32 = 1 << 9;
+
+/// Capabilities this guest was built with.
+pub const CAPABILITIES: u32 = {
+	let mut capabilities = 0;
+	if cfg!(feature = "alloc-zeroed") { capabilities |= ALLOC_ZEROED }
+	if cfg!(feature = "panic-handler") { capabilities |= PANIC_HANDLER }
+	if cfg!(feature = "panic-report") { capabilities |= PANIC_REPORT }
+	if cfg!(feature = "debug-log") { capabilities |= DEBUG_LOG }
+	if cfg!(feature = "log") { capabilities |= LOG }
+	if cfg!(all(feature = "memory-exports", target_arch = "wasm32")) {
+		capabilities |= MEMORY_EXPORTS
+	}
+	if cfg!(feature = "stats") { capabilities |= STATS }
+	if cfg!(feature = "leak-tracking") { capabilities |= LEAK_TRACKING }
+	if cfg!(feature = "abi-check") { capabilities |= ABI_CHECK }
+	if cfg!(feature = "wasi") { capabilities |= WASI }
+	capabilities
+};
+
+/// Export of [`VERSION`].
+#[export_name = "__rs_wasm_alloc_abi_version"]
+pub extern "C" fn export_version() -> u32 {
+	VERSION
+}
+
+/// Export of [`CAPABILITIES`].
+#[export_name = "__rs_wasm_alloc_capabilities"]
+pub extern "C" fn export_capabilities() -> u32 {
+	CAPABILITIES
+}
+
+/// Returns the version of the host, from `alloc.abi_version`.
+#[cfg(feature = "abi-check")]
+#[inline]
+pub fn host_version() -> u32 {
+	unsafe { crate::imports::abi_version() }
+}
+
+/// Panics if [`host_version`] differs from [`VERSION`], with
+/// [`AbortReason::AbiMismatch`](crate::AbortReason::AbiMismatch).
+///
+/// [`ExternAllocator`](crate::ExternAllocator) calls this before its first
+/// allocation.
+#[cfg(feature = "abi-check")]
+#[track_caller]
+pub fn check() {
+	let host_version = host_version();
+	if host_version != VERSION {
+		crate::abort(
+			crate::AbortReason::AbiMismatch,
+			format_args!(
+				"host implements version {host_version} of the rs-wasm-alloc ABI, \
+				but the guest was built for version {VERSION}"
+			)
+		)
+	}
+}
+
+#[cfg(feature = "abi-check")]
+pub(crate) fn check_once() {
+	use core::sync::atomic::{
+		AtomicBool,
+		Ordering
+	};
+
+	static CHECKED: AtomicBool = AtomicBool::new(false);
+	if !CHECKED.swap(true, Ordering::Relaxed) {
+		check()
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32"), feature = "abi-check"))]
+mod tests {
+	use super::*;
+	use crate::mock;
+
+	// A mismatch is not tested, as the abort reason it sets would leak into
+	// the other tests.
+	#[test]
+	fn host_version_is_checked() {
+		check();
+		mock::set_abi_version(VERSION + 1);
+		assert_eq!(host_version(), VERSION + 1);
+		mock::set_abi_version(VERSION);
+	}
+}
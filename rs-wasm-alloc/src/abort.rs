@@ -0,0 +1,143 @@
+//! Reasons for aborting the guest and kinds of panic payloads, passed to the
+//! host with the panic.
+
+use core::fmt::{
+	self,
+	Display,
+	Write
+};
+use core::panic::PanicMessage;
+use core::sync::atomic::{
+	AtomicU32,
+	Ordering
+};
+
+/// Reason for a panic, passed to the host as a `u32`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum AbortReason {
+	/// Not reported by the guest.
+	#[default]
+	Unknown = 0,
+	/// Panic of the guest's own code.
+	Panic = 1,
+	/// Failed allocation, passed to `handle_alloc_error`.
+	AllocFailure = 2,
+	/// Host that does not support the guest's ABI.
+	AbiMismatch = 3,
+	/// Inconsistent state of an allocator in the guest, such as a block that
+	/// `FallbackAllocator` frees twice.
+	CorruptedState = 4,
+	/// Failed `assert!`, `assert_eq!` or `assert_ne!`.
+	Assertion = 5
+}
+
+static REASON: AtomicU32 = AtomicU32::new(AbortReason::Unknown as u32);
+
+/// Sets the reason to report with the next panic, in place of the one
+/// inferred from its message.
+pub fn set_abort_reason(reason: AbortReason) {
+	REASON.store(reason as u32, Ordering::Relaxed);
+}
+
+/// Panics with `message`, reporting `reason` to the host.
+#[track_caller]
+pub fn abort(reason: AbortReason, message: fmt::Arguments<'_>) -> ! {
+	set_abort_reason(reason);
+	panic!("{message}")
+}
+
+impl AbortReason {
+	/// Returns the reason set with [`set_abort_reason`], or the one inferred
+	/// from the start of `message` otherwise.
+	pub fn of(message: impl Display) -> Self {
+		match REASON.load(Ordering::Relaxed) {
+			1 => return Self::Panic,
+			2 => return Self::AllocFailure,
+			3 => return Self::AbiMismatch,
+			4 => return Self::CorruptedState,
+			5 => return Self::Assertion,
+			_ => {}
+		}
+
+		if starts_with(&message, "assertion ") {
+			Self::Assertion
+		} else if starts_with(&message, "memory allocation of ") {
+			Self::AllocFailure
+		} else {
+			Self::Panic
+		}
+	}
+}
+
+/// Kind of the payload of a panic, passed to the host as a `u32`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PayloadKind {
+	/// Not reported by the guest.
+	#[default]
+	Unknown = 0,
+	/// String literal, as with `panic!("oh no")`.
+	Literal = 1,
+	/// Formatted message, as with `panic!("oh {}", "no")`.
+	Formatted = 2,
+	/// `String`, as passed to `std::panic::panic_any`. `core` never hands
+	/// these to the panic handler, but hosts can report them the same way.
+	String = 3
+}
+
+impl PayloadKind {
+	/// Returns the kind of the payload of a panic with `message`.
+	pub fn of(message: &PanicMessage<'_>) -> Self {
+		match message.as_str() {
+			Some(_) => Self::Literal,
+			None => Self::Formatted
+		}
+	}
+}
+
+/// Returns whether `message` starts with `prefix`, formatting no more of it
+/// than needed.
+fn starts_with(message: impl Display, prefix: &str) -> bool {
+	struct Prefix<'a> {
+		rest: &'a str
+	}
+
+	impl Write for Prefix<'_> {
+		fn write_str(&mut self, s: &str) -> fmt::Result {
+			let len = s.len().min(self.rest.len());
+			if s.as_bytes()[..len] != self.rest.as_bytes()[..len] {
+				return Err(fmt::Error)
+			}
+			self.rest = &self.rest[len..];
+			if self.rest.is_empty() { Err(fmt::Error) } else { Ok(()) }
+		}
+	}
+
+	let mut writer = Prefix {
+		rest: prefix
+	};
+	let _ = write!(writer, "{message}");
+	writer.rest.is_empty()
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reasons_are_inferred_from_messages() {
+		let (left, right) = (1, 2);
+		assert_eq!(AbortReason::of("assertion failed: ok"), AbortReason::Assertion);
+		assert_eq!(
+			AbortReason::of(format_args!("assertion `left == right` failed: {left} {right}")),
+			AbortReason::Assertion
+		);
+		assert_eq!(
+			AbortReason::of(format_args!("memory allocation of {} bytes failed", 64)),
+			AbortReason::AllocFailure
+		);
+		assert_eq!(AbortReason::of("assert"), AbortReason::Panic);
+		assert_eq!(AbortReason::of("oh no"), AbortReason::Panic);
+	}
+}
@@ -0,0 +1,144 @@
+//! [`Allocator`] implementation of [`ExternAllocator`], for collections that
+//! allocate from the host while the global allocator is another one.
+//!
+//! Zero-sized blocks are dangling pointers that never reach the host. Growing
+//! and shrinking goes through `alloc.realloc` unless the alignment changes.
+
+use core::alloc::{
+	AllocError,
+	Allocator,
+	GlobalAlloc,
+	Layout
+};
+use core::ptr::{
+	self,
+	NonNull
+};
+
+use crate::ExternAllocator;
+
+fn dangling(layout: &Layout) -> NonNull<[u8]> {
+	let ptr = ptr::without_provenance_mut::<u8>(layout.align());
+	NonNull::slice_from_raw_parts(unsafe { NonNull::new_unchecked(ptr) }, 0)
+}
+
+fn block(ptr: *mut u8, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+	NonNull::new(ptr)
+		.map(|ptr| NonNull::slice_from_raw_parts(ptr, size))
+		.ok_or(AllocError)
+}
+
+impl ExternAllocator {
+	/// Moves the block at `ptr` to `new_layout`, as `realloc` does if the
+	/// alignment stays the same.
+	unsafe fn resize(
+		&self,
+		ptr: NonNull<u8>, old_layout: Layout,
+		new_layout: Layout
+	) -> Result<NonNull<[u8]>, AllocError> {
+		if old_layout.size() != 0 && old_layout.align() == new_layout.align() {
+			let new_ptr = GlobalAlloc::realloc(self, ptr.as_ptr(), old_layout, new_layout.size());
+			return block(new_ptr, new_layout.size())
+		}
+
+		let new_block = self.allocate(new_layout)?;
+		let size = old_layout.size().min(new_layout.size());
+		ptr::copy_nonoverlapping(ptr.as_ptr(), new_block.cast().as_ptr(), size);
+		self.deallocate(ptr, old_layout);
+		Ok(new_block)
+	}
+}
+
+unsafe impl Allocator for ExternAllocator {
+	fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+		match layout.size() {
+			0 => Ok(dangling(&layout)),
+			size => block(unsafe { GlobalAlloc::alloc(self, layout) }, size)
+		}
+	}
+
+	fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+		match layout.size() {
+			0 => Ok(dangling(&layout)),
+			size => block(unsafe { GlobalAlloc::alloc_zeroed(self, layout) }, size)
+		}
+	}
+
+	unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+		if layout.size() != 0 {
+			GlobalAlloc::dealloc(self, ptr.as_ptr(), layout)
+		}
+	}
+
+	unsafe fn grow(
+		&self,
+		ptr: NonNull<u8>, old_layout: Layout,
+		new_layout: Layout
+	) -> Result<NonNull<[u8]>, AllocError> {
+		self.resize(ptr, old_layout, new_layout)
+	}
+
+	unsafe fn grow_zeroed(
+		&self,
+		ptr: NonNull<u8>, old_layout: Layout,
+		new_layout: Layout
+	) -> Result<NonNull<[u8]>, AllocError> {
+		let new_block = self.resize(ptr, old_layout, new_layout)?;
+		new_block.cast::<u8>().as_ptr()
+			.add(old_layout.size())
+			.write_bytes(0, new_layout.size() - old_layout.size());
+		Ok(new_block)
+	}
+
+	unsafe fn shrink(
+		&self,
+		ptr: NonNull<u8>, old_layout: Layout,
+		new_layout: Layout
+	) -> Result<NonNull<[u8]>, AllocError> {
+		if new_layout.size() == 0 {
+			self.deallocate(ptr, old_layout);
+			return Ok(dangling(&new_layout))
+		}
+		self.resize(ptr, old_layout, new_layout)
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	extern crate std;
+
+	use std::vec::Vec;
+
+	use super::*;
+	use crate::mock;
+
+	#[test]
+	fn collections_allocate_from_the_host() {
+		let mut vec = Vec::new_in(ExternAllocator);
+		vec.extend(0..100u32);
+		assert_eq!(mock::block(vec.as_ptr().cast()).map(|block| block.used), Some(true));
+		vec.truncate(10);
+		vec.shrink_to_fit();
+		assert_eq!(mock::block(vec.as_ptr().cast()).map(|block| block.size), Some(40));
+		assert!(vec.iter().copied().eq(0..10));
+	}
+
+	#[test]
+	fn grow_zeroed_zeroes_the_new_bytes() {
+		unsafe {
+			let old_layout = Layout::from_size_align(8, 4).unwrap();
+			let ptr = ExternAllocator.allocate(old_layout).unwrap().cast::<u8>();
+			ptr.as_ptr().write_bytes(0xAB, 8);
+
+			let new_layout = Layout::from_size_align(64, 16).unwrap();
+			let new_block = ExternAllocator.grow_zeroed(ptr, old_layout, new_layout).unwrap();
+			let bytes = new_block.as_ref();
+			assert!(bytes[..8].iter().all(|&byte| byte == 0xAB));
+			assert!(bytes[8..].iter().all(|&byte| byte == 0));
+			assert_eq!(new_block.cast::<u8>().align_offset(16), 0);
+
+			let empty = ExternAllocator.shrink(new_block.cast(), new_layout, Layout::new::<()>());
+			assert_eq!(empty.map(|block| block.len()), Ok(0));
+		}
+	}
+}
@@ -0,0 +1,92 @@
+//! Free blocks kept in the guest, shared by the allocators that manage some of
+//! their memory themselves.
+//!
+//! Free blocks are linked through the blocks themselves. The allocators in
+//! front of [`ExternAllocator`] also share how they reallocate across size
+//! classes.
+
+#[cfg(any(feature = "hybrid", feature = "free-cache"))]
+use core::alloc::{
+	GlobalAlloc,
+	Layout
+};
+#[cfg(any(feature = "hybrid", feature = "free-cache"))]
+use core::ptr::{
+	self,
+	null_mut
+};
+
+#[cfg(any(feature = "hybrid", feature = "free-cache"))]
+use crate::ExternAllocator;
+
+/// Free block, stored at its own address, with `value` after the link.
+pub(crate) struct Node<T = ()> {
+	pub(crate) next: *mut Node<T>,
+	#[cfg_attr(not(feature = "fallback"), allow(dead_code))]
+	pub(crate) value: T
+}
+
+/// Stack of free blocks of the same size.
+#[cfg(any(feature = "hybrid", feature = "free-cache"))]
+pub(crate) struct FreeStack {
+	head: *mut Node
+}
+
+#[cfg(any(feature = "hybrid", feature = "free-cache"))]
+unsafe impl Send for FreeStack {}
+
+#[cfg(any(feature = "hybrid", feature = "free-cache"))]
+impl FreeStack {
+	pub(crate) const EMPTY: Self = Self { head: null_mut() };
+
+	/// # Safety
+	/// `ptr` must be valid for writes of a [`Node`], aligned for it, and
+	/// unused until it is popped again.
+	pub(crate) unsafe fn push(&mut self, ptr: *mut u8) {
+		let node = ptr.cast::<Node>();
+		node.write(Node {
+			next: self.head,
+			value: ()
+		});
+		self.head = node;
+	}
+
+	pub(crate) fn pop(&mut self) -> Option<*mut u8> {
+		let node = self.head;
+		if node.is_null() {
+			return None
+		}
+		self.head = unsafe { (*node).next };
+		Some(node.cast())
+	}
+}
+
+/// Reallocates `ptr` for `alloc`, which serves the layouts that `class` maps
+/// to a size class itself and forwards the others to [`ExternAllocator`].
+///
+/// Blocks stay in place within their class, go to the host between two
+/// forwarded layouts, and are copied otherwise.
+///
+/// # Safety
+/// As for [`GlobalAlloc::realloc`].
+#[cfg(any(feature = "hybrid", feature = "free-cache"))]
+pub(crate) unsafe fn realloc_by_class<A: GlobalAlloc>(
+	alloc: &A,
+	class: impl Fn(&Layout) -> Option<usize>,
+	ptr: *mut u8, layout: Layout,
+	new_size: usize
+) -> *mut u8 {
+	let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+	match (class(&layout), class(&new_layout)) {
+		(None, None) => ExternAllocator.realloc(ptr, layout, new_size),
+		(Some(class), Some(new_class)) if class == new_class => ptr,
+		_ => {
+			let new_ptr = alloc.alloc(new_layout);
+			if !new_ptr.is_null() {
+				ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+				alloc.dealloc(ptr, layout);
+			}
+			new_ptr
+		}
+	}
+}
@@ -0,0 +1,105 @@
+//! Guest-side buffering of writes to the host.
+
+use core::fmt::{
+	self,
+	Write
+};
+
+/// Writer that batches writes to `W` in a buffer of `N` bytes.
+///
+/// The buffer is written to `W` in one call when a write does not fit, on
+/// [`send`](Self::send), and on drop. Strings are never split, so that each
+/// call to `W` is still valid UTF-8, and strings longer than `N` are written
+/// directly.
+#[derive(Debug)]
+pub struct Buffered<W: Write, const N: usize = 256> {
+	inner: W,
+	buf: [u8; N],
+	len: usize
+}
+
+impl<W: Write, const N: usize> Buffered<W, N> {
+	pub const fn new(inner: W) -> Self {
+		Self {
+			inner,
+			buf: [0; N],
+			len: 0
+		}
+	}
+
+	#[inline]
+	pub fn get_mut(&mut self) -> &mut W {
+		&mut self.inner
+	}
+
+	/// Writes the buffer to `W`.
+	pub fn send(&mut self) {
+		if self.len == 0 { return }
+
+		let buffered = unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) };
+		let _ = self.inner.write_str(buffered);
+		self.len = 0;
+	}
+}
+
+#[cfg(feature = "debug-log")]
+impl<I: crate::DebugImports, const N: usize> Buffered<crate::DebugLog<I>, N> {
+	/// Writes the buffer to the host and ends the message.
+	pub fn flush(&mut self) {
+		self.send();
+		self.inner.flush();
+	}
+}
+
+impl<W: Write, const N: usize> Write for Buffered<W, N> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		if s.len() > N - self.len {
+			self.send();
+			if s.len() > N {
+				return self.inner.write_str(s)
+			}
+		}
+		self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+		self.len += s.len();
+		Ok(())
+	}
+}
+
+impl<W: Write, const N: usize> Drop for Buffered<W, N> {
+	fn drop(&mut self) {
+		self.send();
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32"), feature = "debug-log"))]
+mod tests {
+	use super::*;
+	use crate::mock;
+	use crate::DebugLog;
+
+	#[derive(Debug)]
+	#[allow(dead_code)]
+	struct Point {
+		x: i32,
+		y: i32
+	}
+
+	#[test]
+	fn writes_are_batched_until_full_or_flushed() {
+		let mut log = Buffered::<_, 16>::new(DebugLog::new());
+		let _ = write!(log, "{:?}", Point { x: 1, y: -2 });
+		log.flush();
+		assert_eq!(mock::take_debug_writes(), 2);
+
+		let _ = log.write_str("0123456789abcdefg");
+		let _ = log.write_char('é');
+		drop(log);
+		DebugLog::new().flush();
+		assert_eq!(mock::take_debug_writes(), 2);
+
+		assert_eq!(mock::take_debug_log(), [
+			"Point { x: 1, y: -2 }",
+			"0123456789abcdefgé"
+		]);
+	}
+}
@@ -0,0 +1,226 @@
+//! Cache of freed blocks in front of [`ExternAllocator`], to save host calls
+//! when blocks of the same size are allocated and freed over and over.
+//!
+//! Cacheable layouts are rounded up to a power-of-two size class, which is
+//! the size the host allocates them with, so that any cached block of a class
+//! can serve any request of that class and be freed to the host again.
+
+use core::alloc::{
+	GlobalAlloc,
+	Layout
+};
+use crate::ExternAllocator;
+use crate::blocks::{
+	self,
+	FreeStack
+};
+use crate::lock::Lock;
+
+/// Largest size class that is cached.
+pub const MAX_CACHED_SIZE: usize = 4096;
+
+/// Largest alignment that is cached, and the one cached blocks are allocated
+/// with.
+pub const CACHED_ALIGN: usize = 16;
+
+/// Smallest size class.
+const MIN_CLASS: usize = 16;
+
+const CLASSES: usize =
+	(MAX_CACHED_SIZE.trailing_zeros() - MIN_CLASS.trailing_zeros() + 1) as usize;
+
+/// Bytes that a [`CachedAllocator`] may keep cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLimits {
+	/// Bytes cached in every size class.
+	pub class_bytes: usize,
+	/// Bytes cached in all size classes together.
+	pub total_bytes: usize
+}
+
+impl CacheLimits {
+	pub const DEFAULT: Self = Self {
+		class_bytes: 16 * 1024,
+		total_bytes: 64 * 1024
+	};
+}
+
+impl Default for CacheLimits {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+
+/// Allocator that forwards every request to [`ExternAllocator`], but keeps
+/// freed blocks of up to [`MAX_CACHED_SIZE`] bytes for later requests.
+///
+/// Every `CachedAllocator` shares the same cache, limited by the limits of the
+/// one that frees a block. Cached blocks are still live for the host, and for
+/// the `stats` and `leak-tracking` features, until they are [trimmed].
+///
+/// [trimmed]: Self::trim
+#[derive(Debug, PartialEq, Eq)]
+pub struct CachedAllocator {
+	limits: CacheLimits
+}
+
+impl CachedAllocator {
+	pub const fn new(limits: CacheLimits) -> Self {
+		Self { limits }
+	}
+
+	#[inline]
+	pub const fn limits(&self) -> CacheLimits {
+		self.limits
+	}
+
+	/// Frees every cached block to the host.
+	pub fn trim() {
+		let mut cache = CACHE.lock();
+		for class in 0..CLASSES {
+			let layout = class_layout(class);
+			while let Some(ptr) = cache.take(class) {
+				unsafe { ExternAllocator.dealloc(ptr, layout) };
+			}
+		}
+	}
+
+	/// Returns the number of bytes cached.
+	pub fn cached_bytes() -> usize {
+		CACHE.lock().total_bytes
+	}
+}
+
+impl Default for CachedAllocator {
+	fn default() -> Self {
+		Self::new(CacheLimits::DEFAULT)
+	}
+}
+
+/// Returns the size class of `layout`, or `None` if it is not cached.
+#[inline]
+fn class(layout: &Layout) -> Option<usize> {
+	if layout.size() > MAX_CACHED_SIZE || layout.align() > CACHED_ALIGN {
+		return None
+	}
+	let size = layout.size().max(MIN_CLASS).next_power_of_two();
+	Some((size.trailing_zeros() - MIN_CLASS.trailing_zeros()) as usize)
+}
+
+/// Returns the layout the host allocates the blocks of `class` with.
+#[inline]
+fn class_layout(class: usize) -> Layout {
+	unsafe { Layout::from_size_align_unchecked(MIN_CLASS << class, CACHED_ALIGN) }
+}
+
+struct Cache {
+	free: [FreeStack; CLASSES],
+	class_bytes: [usize; CLASSES],
+	total_bytes: usize
+}
+
+static CACHE: Lock<Cache> = Lock::new(Cache {
+	free: [FreeStack::EMPTY; CLASSES],
+	class_bytes: [0; CLASSES],
+	total_bytes: 0
+});
+
+impl Cache {
+	fn take(&mut self, class: usize) -> Option<*mut u8> {
+		let block = self.free[class].pop()?;
+		self.class_bytes[class] -= MIN_CLASS << class;
+		self.total_bytes -= MIN_CLASS << class;
+		Some(block)
+	}
+
+	/// Caches `ptr` unless that would exceed `limits`, returning whether it did.
+	unsafe fn put(&mut self, class: usize, ptr: *mut u8, limits: &CacheLimits) -> bool {
+		let size = MIN_CLASS << class;
+		if self.class_bytes[class] + size > limits.class_bytes
+			|| self.total_bytes + size > limits.total_bytes
+		{
+			return false
+		}
+		self.free[class].push(ptr);
+		self.class_bytes[class] += size;
+		self.total_bytes += size;
+		true
+	}
+}
+
+unsafe impl GlobalAlloc for CachedAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		match class(&layout) {
+			Some(class) => {
+				let cached = CACHE.lock().take(class);
+				cached.unwrap_or_else(|| ExternAllocator.alloc(class_layout(class)))
+			},
+			None => ExternAllocator.alloc(layout)
+		}
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		match class(&layout) {
+			Some(class) => {
+				if !CACHE.lock().put(class, ptr, &self.limits) {
+					ExternAllocator.dealloc(ptr, class_layout(class));
+				}
+			},
+			None => ExternAllocator.dealloc(ptr, layout)
+		}
+	}
+
+	unsafe fn realloc(
+		&self,
+		ptr: *mut u8, layout: Layout,
+		new_size: usize
+	) -> *mut u8 {
+		blocks::realloc_by_class(self, class, ptr, layout, new_size)
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use super::*;
+	use crate::mock;
+
+	#[test]
+	fn freed_blocks_are_reused_until_trimmed() {
+		let cached = CachedAllocator::new(CacheLimits {
+			class_bytes: 64,
+			total_bytes: 64
+		});
+		let layout = Layout::from_size_align(40, 8).unwrap();
+		unsafe {
+			let a = cached.alloc(layout);
+			let b = cached.alloc(layout);
+			assert_eq!(mock::block(a).map(|block| block.size), Some(64));
+
+			cached.dealloc(a, layout);
+			assert_eq!(CachedAllocator::cached_bytes(), 64);
+			// Over the limit of the class.
+			cached.dealloc(b, layout);
+			assert_eq!(mock::block(b).map(|block| block.used), Some(false));
+
+			let c_layout = Layout::from_size_align(33, 1).unwrap();
+			let c = cached.alloc(c_layout);
+			assert_eq!(c, a);
+			cached.dealloc(c, c_layout);
+
+			CachedAllocator::trim();
+			assert_eq!(CachedAllocator::cached_bytes(), 0);
+			assert_eq!(mock::block(a).map(|block| block.used), Some(false));
+		}
+	}
+
+	#[test]
+	fn large_blocks_are_not_cached() {
+		let layout = Layout::from_size_align(MAX_CACHED_SIZE + 1, 8).unwrap();
+		unsafe {
+			let ptr = CachedAllocator::default().alloc(layout);
+			assert_eq!(mock::block(ptr).map(|block| block.size), Some(layout.size()));
+			CachedAllocator::default().dealloc(ptr, layout);
+			assert_eq!(mock::block(ptr).map(|block| block.used), Some(false));
+		}
+	}
+}
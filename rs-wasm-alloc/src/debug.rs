@@ -0,0 +1,139 @@
+//! Debug log output through the host.
+//!
+//! Output goes to the host's `debug` module, or to any other module that
+//! implements [`DebugImports`], such as the `debug_module` of
+//! [`define_extern_allocator!`](crate::define_extern_allocator).
+
+use core::fmt::{
+	self,
+	Write
+};
+use core::marker::PhantomData;
+
+/// Functions of a `debug` import module, that [`DebugLog`],
+/// [`print`](crate::print) and, with the `log` feature, `logger` write to.
+pub trait DebugImports {
+	/// # Safety
+	/// `ch` must be a valid `char`.
+	unsafe fn dblog_ch(ch: u32);
+	/// # Safety
+	/// `ptr` must point to `len` bytes of UTF-8.
+	unsafe fn dblog_str(ptr: *const u8, len: usize);
+	fn dblog_flush();
+	/// # Safety
+	/// `ptr` must point to `len` bytes of UTF-8.
+	unsafe fn dblog_write(stream: u32, ptr: *const u8, len: usize);
+	fn dblog_flush_stream(stream: u32);
+	/// # Safety
+	/// Each pointer must be null or point to as many bytes of UTF-8 as its
+	/// length, except `target`, which must not be null.
+	#[cfg(feature = "log")]
+	#[allow(clippy::too_many_arguments)]
+	unsafe fn dblog_record(
+		level: u32,
+		target: *const u8, target_len: usize,
+		module_path: *const u8, module_path_len: usize,
+		file: *const u8, file_len: usize,
+		line: u32
+	);
+}
+
+/// The host's `debug` module.
+#[derive(Debug)]
+pub struct HostDebug;
+
+impl DebugImports for HostDebug {
+	unsafe fn dblog_ch(ch: u32) {
+		crate::imports::dblog_ch(ch)
+	}
+
+	unsafe fn dblog_str(ptr: *const u8, len: usize) {
+		crate::imports::dblog_str(ptr, len)
+	}
+
+	fn dblog_flush() {
+		unsafe { crate::imports::dblog_flush() }
+	}
+
+	unsafe fn dblog_write(stream: u32, ptr: *const u8, len: usize) {
+		crate::imports::dblog_write(stream, ptr, len)
+	}
+
+	fn dblog_flush_stream(stream: u32) {
+		unsafe { crate::imports::dblog_flush_stream(stream) }
+	}
+
+	#[cfg(feature = "log")]
+	unsafe fn dblog_record(
+		level: u32,
+		target: *const u8, target_len: usize,
+		module_path: *const u8, module_path_len: usize,
+		file: *const u8, file_len: usize,
+		line: u32
+	) {
+		crate::imports::dblog_record(
+			level,
+			target, target_len,
+			module_path, module_path_len,
+			file, file_len,
+			line
+		)
+	}
+}
+
+/// Writer that sends its output to the debug log of `I`, the host's `debug`
+/// module by default.
+///
+/// Every write is a call to the host, which [`Buffered`](crate::Buffered) can
+/// batch.
+#[derive(Debug)]
+pub struct DebugLog<I = HostDebug>(PhantomData<fn() -> I>);
+
+impl DebugLog {
+	#[inline]
+	pub const fn new() -> Self {
+		Self(PhantomData)
+	}
+}
+
+impl<I: DebugImports> DebugLog<I> {
+	#[inline]
+	pub fn flush(&mut self) {
+		I::dblog_flush()
+	}
+}
+
+impl<I> Default for DebugLog<I> {
+	fn default() -> Self {
+		Self(PhantomData)
+	}
+}
+
+impl<I: DebugImports> Write for DebugLog<I> {
+	fn write_char(&mut self, ch: char) -> fmt::Result {
+		unsafe { I::dblog_ch(ch as u32) };
+		Ok(())
+	}
+
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		unsafe { I::dblog_str(s.as_ptr(), s.len()) };
+		Ok(())
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use super::*;
+	use crate::mock;
+
+	#[test]
+	fn writes_are_flushed_as_one_message() {
+		let (ch, str) = ('a', "b");
+		let mut log = DebugLog::new();
+		let _ = write!(log, "{ch} {str:?}");
+		log.flush();
+		let _ = writeln!(log, "{}", 42);
+		log.flush();
+		assert_eq!(mock::take_debug_log(), ["a \"b\"", "42\n"]);
+	}
+}
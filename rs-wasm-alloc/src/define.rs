@@ -0,0 +1,507 @@
+//! Allocators and panic handlers bound to custom import modules, with
+//! [`define_extern_allocator!`](crate::define_extern_allocator).
+//!
+//! Everything else in this module is used by the generated code only.
+
+#[cfg(target_arch = "wasm32")]
+pub use crate::panic::handle_panic;
+pub use crate::panic::PanicImports;
+
+/// Defines an allocator like [`ExternAllocator`](crate::ExternAllocator),
+/// importing from a custom module instead of `alloc`.
+///
+/// ```ignore
+/// rs_wasm_alloc::define_extern_allocator!(module = "myhost_alloc");
+///
+/// rs_wasm_alloc::define_extern_allocator! {
+///     /// Allocator of the other ABI.
+///     pub struct OtherAllocator;
+///     module = "other_alloc",
+///     panic_module = "other_panic",
+///     debug_module = "other_debug",
+///     prefix = "other_"
+/// }
+/// ```
+///
+/// The first form defines `pub struct ExternAllocator`. The keys must come in
+/// the order above:
+/// - `module` names the module of `alloc`, `dealloc`, `realloc` and `oom`.
+/// - `panic_module` also defines a `#[panic_handler]` that reports to the
+///   `panic`, `panic_put_file`, `panic_put_line_column`,
+///   `panic_put_payload_kind`, `panic_put_abort_reason`, `panic_str` and
+///   `panic_nested` functions of that module, like the handler of the
+///   `panic-handler` feature, which must be disabled then. Panic hooks run as
+///   usual, but panics cannot be reported in one call.
+/// - `debug_module` also implements `DebugImports` for the allocator over the
+///   `dblog_ch`, `dblog_str`, `dblog_flush`, `dblog_write`,
+///   `dblog_flush_stream` and, with the `log` feature, `dblog_record`
+///   functions of that module. `DebugLog::<Allocator>`,
+///   `print::print_with::<Allocator>` and `logger::init_with::<Allocator>`
+///   write to it. Requires the `debug-log` feature.
+/// - `prefix` is prepended to the name of every imported function.
+///
+/// Allocations are counted by the `stats` and `leak-tracking` features like
+/// those of `ExternAllocator`. On other targets than `wasm32`, the allocator
+/// uses the same mock host as `ExternAllocator`.
+#[macro_export]
+macro_rules! define_extern_allocator {
+	(
+		module = $module:literal
+		$(, panic_module = $panic_module:literal)?
+		$(, debug_module = $debug_module:literal)?
+		$(, prefix = $prefix:literal)?
+		$(,)?
+	) => {
+		$crate::define_extern_allocator! {
+			/// Allocator that forwards every request to the host.
+			pub struct ExternAllocator;
+			module = $module
+			$(, panic_module = $panic_module)?
+			$(, debug_module = $debug_module)?
+			$(, prefix = $prefix)?
+		}
+	};
+
+	(
+		$(#[$attr:meta])*
+		$vis:vis struct $name:ident;
+		module = $module:literal
+		$(, panic_module = $panic_module:literal)?
+		$(, debug_module = $debug_module:literal)?
+		$(, prefix = $prefix:literal)?
+		$(,)?
+	) => {
+		$(#[$attr])*
+		#[derive(Debug, PartialEq, Eq)]
+		$vis struct $name;
+
+		const _: () = {
+			use ::core::alloc::{
+				GlobalAlloc,
+				Layout
+			};
+
+			#[cfg(target_arch = "wasm32")]
+			#[link(wasm_import_module = $module)]
+			extern "C" {
+				#[link_name = ::core::concat!($($prefix,)? "alloc")]
+				fn host_alloc(size: usize, alignment: usize) -> *mut u8;
+				#[link_name = ::core::concat!($($prefix,)? "dealloc")]
+				fn host_dealloc(ptr: *mut u8, size: usize, alignment: usize);
+				#[link_name = ::core::concat!($($prefix,)? "realloc")]
+				fn host_realloc(
+					ptr: *mut u8, size: usize, alignment: usize,
+					new_size: usize
+				) -> *mut u8;
+				#[link_name = ::core::concat!($($prefix,)? "oom")]
+				fn host_oom(size: usize, alignment: usize);
+			}
+
+			#[cfg(not(target_arch = "wasm32"))]
+			use $crate::define::mock::{
+				alloc as host_alloc,
+				dealloc as host_dealloc,
+				oom as host_oom,
+				realloc as host_realloc
+			};
+
+			unsafe impl GlobalAlloc for $name {
+				unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+					let (size, align) = (layout.size(), layout.align());
+					let ptr = host_alloc(size, align);
+					if ptr.is_null() {
+						$crate::define::record_oom();
+						host_oom(size, align);
+					}
+					$crate::define::record_alloc(ptr, size, align);
+					ptr
+				}
+
+				unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+					host_dealloc(ptr, layout.size(), layout.align());
+					$crate::define::record_dealloc(ptr, layout.size());
+				}
+
+				unsafe fn realloc(
+					&self,
+					ptr: *mut u8, layout: Layout,
+					new_size: usize
+				) -> *mut u8 {
+					let (size, align) = (layout.size(), layout.align());
+					let new_ptr = host_realloc(ptr, size, align, new_size);
+					if new_ptr.is_null() {
+						$crate::define::record_oom();
+						host_oom(new_size, align);
+					}
+					$crate::define::record_realloc(ptr, new_ptr, size, new_size);
+					new_ptr
+				}
+			}
+		};
+
+		$crate::__define_panic_handler!(
+			[$($prefix)?]
+			$($panic_module)?
+		);
+
+		$crate::__define_debug_imports!(
+			$name [$($prefix)?]
+			$($debug_module)?
+		);
+	};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __define_panic_handler {
+	([$($prefix:literal)?]) => {};
+
+	([$($prefix:literal)?] $panic_module:literal) => {
+		#[cfg(all(target_arch = "wasm32", not(test)))]
+		const _: () = {
+			#[link(wasm_import_module = $panic_module)]
+			extern "C" {
+				#[link_name = ::core::concat!($($prefix,)? "panic")]
+				fn host_panic() -> !;
+				#[link_name = ::core::concat!($($prefix,)? "panic_put_file")]
+				fn host_panic_put_file(file: *const u8, len: usize);
+				#[link_name = ::core::concat!($($prefix,)? "panic_put_line_column")]
+				fn host_panic_put_line_column(line: usize, col: usize);
+				#[link_name = ::core::concat!($($prefix,)? "panic_put_payload_kind")]
+				fn host_panic_put_payload_kind(kind: u32);
+				#[link_name = ::core::concat!($($prefix,)? "panic_put_abort_reason")]
+				fn host_panic_put_abort_reason(reason: u32);
+				#[link_name = ::core::concat!($($prefix,)? "panic_str")]
+				fn host_panic_str(str: *const u8, len: usize);
+				#[link_name = ::core::concat!($($prefix,)? "panic_nested")]
+				fn host_panic_nested(file: *const u8, len: usize, line: u32) -> !;
+			}
+
+			struct Imports;
+
+			impl $crate::define::PanicImports for Imports {
+				unsafe fn panic() -> ! {
+					host_panic()
+				}
+
+				unsafe fn panic_put_file(file: *const u8, len: usize) {
+					host_panic_put_file(file, len)
+				}
+
+				fn panic_put_line_column(line: usize, col: usize) {
+					unsafe { host_panic_put_line_column(line, col) }
+				}
+
+				fn panic_put_payload_kind(kind: u32) {
+					unsafe { host_panic_put_payload_kind(kind) }
+				}
+
+				fn panic_put_abort_reason(reason: u32) {
+					unsafe { host_panic_put_abort_reason(reason) }
+				}
+
+				unsafe fn panic_str(str: *const u8, len: usize) {
+					host_panic_str(str, len)
+				}
+
+				unsafe fn panic_nested(file: *const u8, len: usize, line: u32) -> ! {
+					host_panic_nested(file, len, line)
+				}
+			}
+
+			#[panic_handler]
+			fn panic_handler(info: &::core::panic::PanicInfo<'_>) -> ! {
+				$crate::define::handle_panic::<Imports>(info)
+			}
+		};
+	};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __define_debug_imports {
+	($name:ident [$($prefix:literal)?]) => {};
+
+	($name:ident [$($prefix:literal)?] $debug_module:literal) => {
+		const _: () = {
+			#[cfg(target_arch = "wasm32")]
+			#[link(wasm_import_module = $debug_module)]
+			extern "C" {
+				#[link_name = ::core::concat!($($prefix,)? "dblog_ch")]
+				fn host_dblog_ch(ch: u32);
+				#[link_name = ::core::concat!($($prefix,)? "dblog_str")]
+				fn host_dblog_str(ptr: *const u8, len: usize);
+				#[link_name = ::core::concat!($($prefix,)? "dblog_flush")]
+				fn host_dblog_flush();
+				#[link_name = ::core::concat!($($prefix,)? "dblog_write")]
+				fn host_dblog_write(stream: u32, ptr: *const u8, len: usize);
+				#[link_name = ::core::concat!($($prefix,)? "dblog_flush_stream")]
+				fn host_dblog_flush_stream(stream: u32);
+				$crate::__with_log! {
+					#[link_name = ::core::concat!($($prefix,)? "dblog_record")]
+					fn host_dblog_record(
+						level: u32,
+						target: *const u8, target_len: usize,
+						module_path: *const u8, module_path_len: usize,
+						file: *const u8, file_len: usize,
+						line: u32
+					);
+				}
+			}
+
+			#[cfg(not(target_arch = "wasm32"))]
+			use $crate::define::mock::{
+				dblog_ch as host_dblog_ch,
+				dblog_flush as host_dblog_flush,
+				dblog_flush_stream as host_dblog_flush_stream,
+				dblog_str as host_dblog_str,
+				dblog_write as host_dblog_write
+			};
+			#[cfg(not(target_arch = "wasm32"))]
+			$crate::__with_log! {
+				use $crate::define::mock::dblog_record as host_dblog_record;
+			}
+
+			impl $crate::DebugImports for $name {
+				unsafe fn dblog_ch(ch: u32) {
+					host_dblog_ch(ch)
+				}
+
+				unsafe fn dblog_str(ptr: *const u8, len: usize) {
+					host_dblog_str(ptr, len)
+				}
+
+				fn dblog_flush() {
+					unsafe { host_dblog_flush() }
+				}
+
+				unsafe fn dblog_write(stream: u32, ptr: *const u8, len: usize) {
+					host_dblog_write(stream, ptr, len)
+				}
+
+				fn dblog_flush_stream(stream: u32) {
+					unsafe { host_dblog_flush_stream(stream) }
+				}
+
+				$crate::__with_log! {
+					unsafe fn dblog_record(
+						level: u32,
+						target: *const u8, target_len: usize,
+						module_path: *const u8, module_path_len: usize,
+						file: *const u8, file_len: usize,
+						line: u32
+					) {
+						host_dblog_record(
+							level,
+							target, target_len,
+							module_path, module_path_len,
+							file, file_len,
+							line
+						)
+					}
+				}
+			}
+		};
+	};
+}
+
+/// Expands to its items with the `log` feature of this crate, whatever the
+/// features of the crate that the macro is expanded in.
+#[cfg(feature = "log")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __with_log {
+	($($item:item)*) => {
+		$($item)*
+	};
+}
+
+#[cfg(not(feature = "log"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __with_log {
+	($($item:item)*) => {};
+}
+
+/// Imports of the mock host, for the generated allocators on other targets
+/// than `wasm32`.
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mock {
+	use crate::imports;
+
+	/// # Safety
+	/// `alignment` must be a valid alignment, and `size` must not overflow
+	/// when rounded up to it.
+	pub unsafe fn alloc(size: usize, alignment: usize) -> *mut u8 {
+		imports::alloc(size, alignment)
+	}
+
+	/// # Safety
+	/// `ptr` must have been returned by the mock host.
+	pub unsafe fn dealloc(ptr: *mut u8, size: usize, alignment: usize) {
+		imports::dealloc(ptr, size, alignment)
+	}
+
+	/// # Safety
+	/// `ptr` must have been returned by the mock host, and `new_size` must not
+	/// overflow when rounded up to `alignment`.
+	pub unsafe fn realloc(
+		ptr: *mut u8, size: usize, alignment: usize,
+		new_size: usize
+	) -> *mut u8 {
+		imports::realloc(ptr, size, alignment, new_size)
+	}
+
+	/// # Safety
+	/// `alignment` must be a valid alignment.
+	pub unsafe fn oom(size: usize, alignment: usize) {
+		imports::oom(size, alignment)
+	}
+
+	/// # Safety
+	/// `ch` must be a valid `char`.
+	#[cfg(feature = "debug-log")]
+	pub unsafe fn dblog_ch(ch: u32) {
+		imports::dblog_ch(ch)
+	}
+
+	/// # Safety
+	/// `ptr` must point to `len` bytes of UTF-8.
+	#[cfg(feature = "debug-log")]
+	pub unsafe fn dblog_str(ptr: *const u8, len: usize) {
+		imports::dblog_str(ptr, len)
+	}
+
+	#[cfg(feature = "debug-log")]
+	pub unsafe fn dblog_flush() {
+		imports::dblog_flush()
+	}
+
+	/// # Safety
+	/// `ptr` must point to `len` bytes of UTF-8.
+	#[cfg(feature = "debug-log")]
+	pub unsafe fn dblog_write(stream: u32, ptr: *const u8, len: usize) {
+		imports::dblog_write(stream, ptr, len)
+	}
+
+	#[cfg(feature = "debug-log")]
+	pub unsafe fn dblog_flush_stream(stream: u32) {
+		imports::dblog_flush_stream(stream)
+	}
+
+	/// # Safety
+	/// Each pointer must be null or point to as many bytes of UTF-8 as its
+	/// length, except `target`, which must not be null.
+	#[cfg(feature = "log")]
+	#[allow(clippy::too_many_arguments)]
+	pub unsafe fn dblog_record(
+		level: u32,
+		target: *const u8, target_len: usize,
+		module_path: *const u8, module_path_len: usize,
+		file: *const u8, file_len: usize,
+		line: u32
+	) {
+		imports::dblog_record(
+			level,
+			target, target_len,
+			module_path, module_path_len,
+			file, file_len,
+			line
+		)
+	}
+}
+
+/// Records a call to the host's `oom`.
+#[inline]
+pub fn record_oom() {
+	#[cfg(feature = "stats")]
+	crate::stats::record_host_call();
+}
+
+/// Records an allocation of the host, which failed if `ptr` is null.
+#[inline]
+pub fn record_alloc(ptr: *mut u8, size: usize, align: usize) {
+	#[cfg(feature = "stats")]
+	crate::stats::record_alloc(ptr, size);
+	#[cfg(feature = "leak-tracking")]
+	crate::leaks::record_alloc(ptr, size, align);
+	let _ = (ptr, size, align);
+}
+
+/// Records a deallocation of the host.
+#[inline]
+pub fn record_dealloc(ptr: *mut u8, size: usize) {
+	#[cfg(feature = "stats")]
+	crate::stats::record_dealloc(size);
+	#[cfg(feature = "leak-tracking")]
+	crate::leaks::record_dealloc(ptr);
+	let _ = (ptr, size);
+}
+
+/// Records a reallocation of the host, which failed if `new_ptr` is null.
+#[inline]
+pub fn record_realloc(ptr: *mut u8, new_ptr: *mut u8, size: usize, new_size: usize) {
+	#[cfg(feature = "stats")]
+	crate::stats::record_realloc(new_ptr, size, new_size);
+	#[cfg(feature = "leak-tracking")]
+	crate::leaks::record_realloc(ptr, new_ptr, new_size);
+	let _ = (ptr, new_ptr, size, new_size);
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use core::alloc::{
+		GlobalAlloc,
+		Layout
+	};
+
+	use crate::mock;
+
+	crate::define_extern_allocator! {
+		struct TestAllocator;
+		module = "test_alloc",
+		prefix = "test_"
+	}
+
+	#[cfg(feature = "debug-log")]
+	crate::define_extern_allocator! {
+		struct DebugAllocator;
+		module = "test_alloc",
+		debug_module = "test_debug",
+		prefix = "test_"
+	}
+
+	#[test]
+	fn defined_allocators_use_the_host() {
+		let layout = Layout::from_size_align(16, 4).unwrap();
+		unsafe {
+			let ptr = TestAllocator.alloc(layout);
+			assert_eq!(mock::block(ptr).map(|block| block.used), Some(true));
+
+			let new_ptr = TestAllocator.realloc(ptr, layout, 32);
+			assert_eq!(mock::block(new_ptr).map(|block| block.size), Some(32));
+
+			TestAllocator.dealloc(new_ptr, Layout::from_size_align(32, 4).unwrap());
+			assert_eq!(mock::block(new_ptr).map(|block| block.used), Some(false));
+		}
+	}
+
+	#[cfg(feature = "debug-log")]
+	#[test]
+	fn defined_debug_modules_are_written_to() {
+		use core::fmt::Write;
+
+		use crate::DebugLog;
+		use crate::print::{
+			self,
+			Stream
+		};
+
+		let mut log = DebugLog::<DebugAllocator>::default();
+		let _ = write!(log, "{}", 42);
+		log.flush();
+		print::print_with::<DebugAllocator>(Stream::Stdout, format_args!("a\nb"));
+		assert_eq!(mock::take_debug_log(), ["42"]);
+		assert_eq!(mock::take_stream_lines(1), ["a"]);
+	}
+}
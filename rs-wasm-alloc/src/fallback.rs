@@ -0,0 +1,282 @@
+//! Allocator that manages its own heap in the guest, for hosts without an
+//! `alloc` module.
+//!
+//! [`FallbackAllocator`] keeps a free list over pages it adds to linear memory
+//! with `memory.grow`. Used as the `#[global_allocator]` instead of
+//! [`ExternAllocator`](crate::ExternAllocator), the `alloc` module is not
+//! imported at all. Otherwise, [`enable`] switches `ExternAllocator` over to it
+//! at runtime.
+//!
+//! The host places its blocks past the heap base too, so only one of them may
+//! allocate in an instance.
+
+use core::alloc::{
+	GlobalAlloc,
+	Layout
+};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{
+	AtomicU8,
+	Ordering
+};
+
+use crate::blocks::Node;
+use crate::lock::Lock;
+use crate::pages::PAGE_SIZE;
+
+/// Allocator that serves every request from a free list in the guest.
+///
+/// Every `FallbackAllocator` shares the same heap. Allocations are not counted
+/// by the `stats` and `leak-tracking` features.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FallbackAllocator;
+
+/// Free block, with its size as the value.
+type FreeBlock = Node<usize>;
+
+/// Granularity of block addresses and sizes, so that every free block can hold
+/// a [`FreeBlock`].
+const UNIT: usize = size_of::<FreeBlock>();
+
+/// Free blocks sorted by address, never adjacent to each other.
+struct FreeList {
+	head: *mut FreeBlock
+}
+
+unsafe impl Send for FreeList {}
+
+static FREE_LIST: Lock<FreeList> = Lock::new(FreeList { head: null_mut() });
+
+impl FreeList {
+	/// Takes `size` bytes aligned to `align` from the first block they fit in.
+	unsafe fn take(&mut self, size: usize, align: usize) -> *mut u8 {
+		let mut link: *mut *mut FreeBlock = &mut self.head;
+		while !(*link).is_null() {
+			let block = *link;
+			let start = block as usize;
+			let end = start + (*block).value;
+			let ptr = start.next_multiple_of(align);
+			if ptr <= end && end - ptr >= size {
+				// What is left on either side stays free, in place.
+				let tail = end - ptr - size;
+				let next = if tail > 0 {
+					let rest = (ptr + size) as *mut FreeBlock;
+					rest.write(Node {
+						next: (*block).next,
+						value: tail
+					});
+					rest
+				} else {
+					(*block).next
+				};
+				if ptr > start {
+					(*block).value = ptr - start;
+					(*block).next = next;
+				} else {
+					*link = next;
+				}
+				return ptr as *mut u8
+			}
+			link = &raw mut (*block).next;
+		}
+		null_mut()
+	}
+
+	/// Frees `size` bytes at `ptr`, merging them with adjacent free blocks.
+	///
+	/// Returns `false`, leaving the list as it was, if the bytes overlap a
+	/// free block, as they do when a block is freed twice.
+	unsafe fn insert(&mut self, ptr: *mut u8, size: usize) -> bool {
+		let addr = ptr as usize;
+		let mut prev: *mut FreeBlock = null_mut();
+		let mut next = self.head;
+		while !next.is_null() && (next as usize) < addr {
+			prev = next;
+			next = (*next).next;
+		}
+		if (!prev.is_null() && prev as usize + (*prev).value > addr)
+			|| (!next.is_null() && addr + size > next as usize)
+		{
+			return false
+		}
+
+		let block = ptr.cast::<FreeBlock>();
+		block.write(Node {
+			next,
+			value: size
+		});
+		if !next.is_null() && addr + size == next as usize {
+			(*block).value += (*next).value;
+			(*block).next = (*next).next;
+		}
+
+		if prev.is_null() {
+			self.head = block;
+		} else if prev as usize + (*prev).value == addr {
+			(*prev).value += (*block).value;
+			(*prev).next = (*block).next;
+		} else {
+			(*prev).next = block;
+		}
+		true
+	}
+}
+
+/// Returns the size of the blocks for `layout`, or `None` if it overflows.
+fn block_size(layout: &Layout) -> Option<usize> {
+	layout.size().max(1).checked_next_multiple_of(UNIT)
+}
+
+/// Adds `pages` pages to linear memory, returning their address, or null if
+/// memory cannot grow.
+#[cfg(target_arch = "wasm32")]
+fn memory_grow(pages: usize) -> *mut u8 {
+	crate::pages::grow(pages)
+		.map_or(null_mut(), |previous| (previous * PAGE_SIZE) as *mut u8)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::imports::memory_grow;
+
+unsafe impl GlobalAlloc for FallbackAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let Some(size) = block_size(&layout) else { return null_mut() };
+		let align = layout.align().max(UNIT);
+
+		let mut free_list = FREE_LIST.lock();
+		let ptr = free_list.take(size, align);
+		if !ptr.is_null() {
+			return ptr
+		}
+
+		let Some(pages) = size
+			.checked_add(align - UNIT)
+			.and_then(|size| size.checked_next_multiple_of(PAGE_SIZE))
+			.map(|size| size / PAGE_SIZE)
+		else {
+			return null_mut()
+		};
+		let pages_ptr = memory_grow(pages);
+		if pages_ptr.is_null() {
+			return null_mut()
+		}
+		free_list.insert(pages_ptr, pages * PAGE_SIZE);
+		free_list.take(size, align)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		if let Some(size) = block_size(&layout) {
+			// Abort once the lock is released, so a panic hook can still allocate.
+			let freed = FREE_LIST.lock().insert(ptr, size);
+			if !freed {
+				crate::abort(
+					crate::AbortReason::CorruptedState,
+					format_args!("block at {ptr:p} freed twice, or overlapping a free block")
+				)
+			}
+		}
+	}
+}
+
+const UNDECIDED: u8 = 0;
+const HOST: u8 = 1;
+const GUEST: u8 = 2;
+
+/// Which heap `ExternAllocator` allocates from, decided on its first
+/// allocation.
+static HEAP: AtomicU8 = AtomicU8::new(UNDECIDED);
+
+/// Makes [`ExternAllocator`](crate::ExternAllocator) serve every request from
+/// the [`FallbackAllocator`] instead of the host's `alloc` module.
+///
+/// Returns whether it did, which it does not if `ExternAllocator` has
+/// allocated from the host already.
+pub fn enable() -> bool {
+	match HEAP.compare_exchange(UNDECIDED, GUEST, Ordering::AcqRel, Ordering::Acquire) {
+		Ok(_) => true,
+		Err(heap) => heap == GUEST
+	}
+}
+
+/// Returns whether `ExternAllocator` allocates from the [`FallbackAllocator`],
+/// settling on the host if [`enable`] has not been called yet.
+#[inline]
+pub(crate) fn enabled() -> bool {
+	match HEAP.load(Ordering::Acquire) {
+		UNDECIDED => !enable_host(),
+		heap => heap == GUEST
+	}
+}
+
+#[cold]
+fn enable_host() -> bool {
+	match HEAP.compare_exchange(UNDECIDED, HOST, Ordering::AcqRel, Ordering::Acquire) {
+		Ok(_) => true,
+		Err(heap) => heap == HOST
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use super::*;
+	use crate::mock;
+
+	#[test]
+	fn blocks_are_aligned_and_reused() {
+		unsafe {
+			let layout = Layout::from_size_align(100, 64).unwrap();
+			let ptr = FallbackAllocator.alloc(layout);
+			assert!(!ptr.is_null() && (ptr as usize).is_multiple_of(64));
+			ptr.write_bytes(0xAB, layout.size());
+
+			let small = Layout::from_size_align(3, 1).unwrap();
+			let other = FallbackAllocator.alloc(small);
+			assert!(!other.is_null() && other != ptr);
+
+			FallbackAllocator.dealloc(ptr, layout);
+			FallbackAllocator.dealloc(other, small);
+			assert_eq!(FallbackAllocator.alloc(layout), ptr);
+			FallbackAllocator.dealloc(ptr, layout);
+
+			let big = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+			let ptr = FallbackAllocator.alloc_zeroed(big);
+			assert!((0..big.size()).all(|i| *ptr.add(i) == 0));
+			FallbackAllocator.dealloc(ptr, big);
+		}
+	}
+
+	#[test]
+	fn exhaustion_returns_null() {
+		mock::set_exhausted(true);
+		let layout = Layout::from_size_align(16 * PAGE_SIZE, 8).unwrap();
+		assert!(unsafe { FallbackAllocator.alloc(layout) }.is_null());
+		mock::set_exhausted(false);
+	}
+
+	#[test]
+	fn host_heap_is_kept_once_used() {
+		unsafe {
+			let layout = Layout::from_size_align(8, 8).unwrap();
+			let ptr = crate::ExternAllocator.alloc(layout);
+			assert!(mock::block(ptr).is_some());
+			assert!(!enable());
+			crate::ExternAllocator.dealloc(ptr, layout);
+		}
+	}
+
+	#[test]
+	fn overlapping_frees_are_refused() {
+		let mut heap = [0usize; 32];
+		let ptr = heap.as_mut_ptr().cast::<u8>();
+		let mut free_list = FreeList { head: null_mut() };
+		unsafe {
+			assert!(free_list.insert(ptr.add(4 * UNIT), 4 * UNIT));
+			assert!(!free_list.insert(ptr.add(4 * UNIT), UNIT));
+			assert!(!free_list.insert(ptr.add(2 * UNIT), 3 * UNIT));
+			assert!(!free_list.insert(ptr.add(6 * UNIT), 4 * UNIT));
+			assert!(free_list.insert(ptr, 4 * UNIT));
+			assert_eq!(free_list.take(8 * UNIT, 1), ptr);
+		}
+	}
+}
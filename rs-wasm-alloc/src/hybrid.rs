@@ -0,0 +1,267 @@
+//! Allocator that serves small blocks from slabs in the guest, and large ones
+//! from the host.
+//!
+//! Small layouts are rounded up to a power-of-two size class. Every class has
+//! a free list of slots, refilled one slab at a time from chunks that
+//! [`ExternAllocator`] gets from the host. Chunks are never given back, so the
+//! host sees a few large blocks instead of many small ones.
+
+use core::alloc::{
+	GlobalAlloc,
+	Layout
+};
+use core::ptr::null_mut;
+
+use crate::ExternAllocator;
+use crate::blocks::{
+	self,
+	FreeStack
+};
+use crate::lock::Lock;
+
+/// Largest threshold, and size class, of a [`HybridAllocator`].
+pub const MAX_THRESHOLD: usize = 2048;
+
+/// Threshold of [`HybridAllocator::default`].
+pub const DEFAULT_THRESHOLD: usize = 256;
+
+/// Size of the chunks allocated from the host.
+pub const CHUNK_SIZE: usize = 65536;
+
+/// Size and alignment of the slabs that chunks are split into, each holding
+/// the slots of a single size class.
+const SLAB_SIZE: usize = 4096;
+
+/// Smallest size class, which fits a free slot on every target.
+const MIN_CLASS: usize = 8;
+
+const CLASSES: usize = (MAX_THRESHOLD.trailing_zeros() - MIN_CLASS.trailing_zeros() + 1) as usize;
+
+/// Allocator that serves layouts up to a threshold from size-class slabs, and
+/// forwards larger ones to [`ExternAllocator`].
+///
+/// Every `HybridAllocator` shares the same slabs, whatever its threshold. Only
+/// the chunks are counted by the `stats` and `leak-tracking` features, the
+/// latter with the `"hybrid chunk"` tag.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HybridAllocator {
+	threshold: usize
+}
+
+impl HybridAllocator {
+	/// Creates an allocator that serves layouts of up to `threshold` bytes from
+	/// slabs.
+	///
+	/// # Panics
+	/// If `threshold` is larger than [`MAX_THRESHOLD`].
+	pub const fn new(threshold: usize) -> Self {
+		assert!(threshold <= MAX_THRESHOLD, "threshold is larger than MAX_THRESHOLD");
+		Self { threshold }
+	}
+
+	#[inline]
+	pub const fn threshold(&self) -> usize {
+		self.threshold
+	}
+
+	/// Returns the size class of `layout`, or `None` if it goes to the host.
+	#[inline]
+	fn class(&self, layout: &Layout) -> Option<usize> {
+		if layout.size() > self.threshold || layout.align() > MAX_THRESHOLD {
+			return None
+		}
+		let size = layout.size().max(layout.align()).max(MIN_CLASS);
+		Some((size.next_power_of_two().trailing_zeros() - MIN_CLASS.trailing_zeros()) as usize)
+	}
+}
+
+impl Default for HybridAllocator {
+	fn default() -> Self {
+		Self::new(DEFAULT_THRESHOLD)
+	}
+}
+
+struct Slabs {
+	free: [FreeStack; CLASSES],
+	/// Rest of the last chunk, not yet split into slabs.
+	chunk: usize,
+	chunk_end: usize
+}
+
+static SLABS: Lock<Slabs> = Lock::new(Slabs {
+	free: [FreeStack::EMPTY; CLASSES],
+	chunk: 0,
+	chunk_end: 0
+});
+
+impl Slabs {
+	/// Takes a free slot of `class`, returning null if there is none left in
+	/// the current chunk either.
+	unsafe fn take(&mut self, class: usize) -> *mut u8 {
+		match self.free[class].pop() {
+			Some(slot) => slot,
+			None if self.refill(class) => self.take(class),
+			None => null_mut()
+		}
+	}
+
+	/// Splits a slab of the current chunk into free slots of `class`,
+	/// returning whether the chunk had one left.
+	unsafe fn refill(&mut self, class: usize) -> bool {
+		if self.chunk == self.chunk_end {
+			return false
+		}
+
+		let slab = self.chunk;
+		self.chunk += SLAB_SIZE;
+		let size = MIN_CLASS << class;
+		for offset in (0..SLAB_SIZE).step_by(size).rev() {
+			self.free[class].push((slab + offset) as *mut u8);
+		}
+		true
+	}
+
+	/// Makes `chunk` the current chunk, returning whether it did, which it
+	/// does not if another one was added since the current one ran out.
+	fn add_chunk(&mut self, chunk: *mut u8) -> bool {
+		if self.chunk != self.chunk_end {
+			return false
+		}
+		self.chunk = chunk as usize;
+		self.chunk_end = self.chunk + CHUNK_SIZE;
+		true
+	}
+}
+
+#[inline]
+fn chunk_layout() -> Layout {
+	unsafe { Layout::from_size_align_unchecked(CHUNK_SIZE, SLAB_SIZE) }
+}
+
+/// Takes a free slot of `class`, getting a new chunk from the host if needed.
+///
+/// The host is called without holding `SLABS`, so that a panic hook run by a
+/// failing call can still allocate.
+unsafe fn take(class: usize) -> *mut u8 {
+	loop {
+		let ptr = SLABS.lock().take(class);
+		if !ptr.is_null() {
+			return ptr
+		}
+
+		#[cfg(feature = "leak-tracking")]
+		let tag = crate::leaks::set_tag(Some("hybrid chunk"));
+		let chunk = ExternAllocator.alloc(chunk_layout());
+		#[cfg(feature = "leak-tracking")]
+		crate::leaks::set_tag(tag);
+		if chunk.is_null() {
+			return null_mut()
+		}
+		if !SLABS.lock().add_chunk(chunk) {
+			ExternAllocator.dealloc(chunk, chunk_layout());
+		}
+	}
+}
+
+unsafe impl GlobalAlloc for HybridAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		match self.class(&layout) {
+			Some(class) => take(class),
+			None => ExternAllocator.alloc(layout)
+		}
+	}
+
+	unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+		match self.class(&layout) {
+			Some(class) => {
+				let ptr = take(class);
+				if !ptr.is_null() {
+					ptr.write_bytes(0, layout.size());
+				}
+				ptr
+			},
+			None => ExternAllocator.alloc_zeroed(layout)
+		}
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		match self.class(&layout) {
+			Some(class) => SLABS.lock().free[class].push(ptr),
+			None => ExternAllocator.dealloc(ptr, layout)
+		}
+	}
+
+	unsafe fn realloc(
+		&self,
+		ptr: *mut u8, layout: Layout,
+		new_size: usize
+	) -> *mut u8 {
+		blocks::realloc_by_class(self, |layout| self.class(layout), ptr, layout, new_size)
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use super::*;
+	use crate::mock;
+
+	static HYBRID: HybridAllocator = HybridAllocator::new(64);
+
+	#[test]
+	fn small_blocks_come_from_slabs() {
+		unsafe {
+			let small = Layout::from_size_align(24, 8).unwrap();
+			let (a, b) = (HYBRID.alloc(small), HYBRID.alloc(small));
+			for ptr in [a, b] {
+				let chunk = mock::block_containing(ptr).map(|(_, block)| block.size);
+				assert_eq!(chunk, Some(CHUNK_SIZE));
+			}
+			assert_eq!(a.align_offset(32), 0);
+			assert_eq!((a as usize).abs_diff(b as usize) % 32, 0);
+
+			let large = Layout::from_size_align(65, 8).unwrap();
+			let c = HYBRID.alloc(large);
+			assert_eq!(mock::block(c).map(|block| block.size), Some(65));
+
+			HYBRID.dealloc(a, small);
+			HYBRID.dealloc(b, small);
+			HYBRID.dealloc(c, large);
+		}
+	}
+
+	#[test]
+	fn realloc_moves_across_the_threshold() {
+		unsafe {
+			let layout = Layout::from_size_align(10, 2).unwrap();
+			let ptr = HYBRID.alloc(layout);
+			ptr.copy_from_nonoverlapping(b"0123456789".as_ptr(), 10);
+			assert_eq!(HYBRID.realloc(ptr, layout, 16), ptr);
+
+			let grown = HYBRID.realloc(ptr, Layout::from_size_align(16, 2).unwrap(), 100);
+			assert_eq!(mock::block(grown).map(|block| block.size), Some(100));
+			assert_eq!(core::slice::from_raw_parts(grown, 10), b"0123456789");
+
+			let shrunk = HYBRID.realloc(grown, Layout::from_size_align(100, 2).unwrap(), 10);
+			assert_eq!(mock::block(grown).map(|block| block.used), Some(false));
+			assert_eq!(core::slice::from_raw_parts(shrunk, 10), b"0123456789");
+			HYBRID.dealloc(shrunk, layout);
+		}
+	}
+
+	#[test]
+	fn chunks_are_only_added_once_the_current_one_runs_out() {
+		let mut slabs = Slabs {
+			free: [FreeStack::EMPTY; CLASSES],
+			chunk: 0,
+			chunk_end: 0
+		};
+		let mut chunk = [0u64; SLAB_SIZE / 8];
+		assert!(slabs.add_chunk(chunk.as_mut_ptr().cast()));
+		assert!(!slabs.add_chunk(null_mut()));
+		slabs.chunk_end = slabs.chunk + SLAB_SIZE;
+		unsafe {
+			assert!(!slabs.take(0).is_null());
+			assert!(slabs.add_chunk(null_mut()));
+		}
+	}
+}
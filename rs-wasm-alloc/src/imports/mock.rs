@@ -0,0 +1,507 @@
+//! Mock host for native targets.
+//!
+//! Blocks are served by the system allocator and tracked the same way as
+//! `AllocModule` in `index.js` does, so that misuse panics with the same
+//! messages the JS host throws. Freed blocks are never handed back to the
+//! system, so that an address is never reused and a double free is always
+//! caught, even with other threads allocating.
+
+extern crate std;
+
+use std::alloc::{
+	GlobalAlloc,
+	Layout,
+	System
+};
+use std::cell::{
+	Cell,
+	RefCell
+};
+use std::collections::BTreeMap;
+use std::ptr;
+use std::string::String;
+use std::sync::{
+	Mutex,
+	MutexGuard,
+	PoisonError
+};
+use std::vec::Vec;
+
+#[cfg(any(feature = "panic-handler", feature = "debug-log"))]
+use crate::text::str_from_raw;
+
+/// Bookkeeping of a block handed out by the mock host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockBlock {
+	pub size: usize,
+	pub align: usize,
+	pub used: bool
+}
+
+/// Payload of the panic raised by the mock `panic` import.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WasmPanic {
+	pub message: String,
+	pub file: Option<String>,
+	pub line: usize,
+	pub column: usize,
+	/// Kind of the payload, as in `PayloadKind`.
+	pub payload_kind: u32,
+	/// Reason for the panic, as in `AbortReason`.
+	pub abort_reason: u32
+}
+
+/// Record logged through the mock `dblog_record` import.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugRecord {
+	pub level: u32,
+	pub target: String,
+	pub module_path: Option<String>,
+	pub file: Option<String>,
+	pub line: u32,
+	pub message: String
+}
+
+static BLOCKS: Mutex<BTreeMap<usize, MockBlock>> = Mutex::new(BTreeMap::new());
+
+std::thread_local! {
+	static IN_HOST: Cell<bool> = const { Cell::new(false) };
+	static EXHAUSTED: Cell<bool> = const { Cell::new(false) };
+	static ABI_VERSION: Cell<u32> = const { Cell::new(crate::abi::VERSION) };
+	static LAST_OOM: Cell<Option<Layout>> = const { Cell::new(None) };
+	static PANIC: RefCell<WasmPanic> = const { RefCell::new(WasmPanic {
+		message: String::new(),
+		file: None,
+		line: 0,
+		column: 0,
+		payload_kind: 0,
+		abort_reason: 0
+	}) };
+	static DBLOG: RefCell<(String, Vec<String>)> = const {
+		RefCell::new((String::new(), Vec::new()))
+	};
+	static DBLOG_WRITES: Cell<usize> = const { Cell::new(0) };
+	static DBLOG_RECORDS: RefCell<Vec<DebugRecord>> = const {
+		RefCell::new(Vec::new())
+	};
+	static STREAMS: RefCell<BTreeMap<u32, (String, Vec<String>)>> = const {
+		RefCell::new(BTreeMap::new())
+	};
+}
+
+/// Returns the bookkeeping of the block at `ptr`, if the mock host has ever
+/// handed it out.
+pub fn block(ptr: *const u8) -> Option<MockBlock> {
+	blocks().get(&(ptr as usize)).copied()
+}
+
+/// Returns the address and bookkeeping of the block that `ptr` points into,
+/// if the mock host has ever handed it out.
+pub fn block_containing(ptr: *const u8) -> Option<(usize, MockBlock)> {
+	let addr = ptr as usize;
+	blocks()
+		.range(..=addr)
+		.next_back()
+		.filter(|(&start, block)| addr - start < block.size)
+		.map(|(&start, &block)| (start, block))
+}
+
+/// Takes the messages flushed to the debug log on this thread so far.
+pub fn take_debug_log() -> Vec<String> {
+	DBLOG.with_borrow_mut(|(_, flushed)| core::mem::take(flushed))
+}
+
+/// Takes the number of `dblog_ch` and `dblog_str` calls on this thread so
+/// far.
+pub fn take_debug_writes() -> usize {
+	DBLOG_WRITES.take()
+}
+
+/// Takes the records logged on this thread so far.
+pub fn take_debug_records() -> Vec<DebugRecord> {
+	DBLOG_RECORDS.take()
+}
+
+/// Takes the lines flushed to `stream` on this thread so far.
+pub fn take_stream_lines(stream: u32) -> Vec<String> {
+	STREAMS.with_borrow_mut(|streams| {
+		streams.get_mut(&stream)
+			.map(|(_, flushed)| core::mem::take(flushed))
+			.unwrap_or_default()
+	})
+}
+
+/// Makes the mock host run out of memory for new blocks on this thread.
+pub fn set_exhausted(exhausted: bool) {
+	EXHAUSTED.set(exhausted);
+}
+
+/// Sets the version that `abi_version` returns on this thread.
+pub fn set_abi_version(version: u32) {
+	ABI_VERSION.set(version);
+}
+
+/// Takes the last layout reported through `oom` on this thread.
+pub fn take_last_oom() -> Option<Layout> {
+	LAST_OOM.take()
+}
+
+fn blocks() -> MutexGuard<'static, BTreeMap<usize, MockBlock>> {
+	BLOCKS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Marks the current thread as being inside the mock host.
+///
+/// The block table allocates, which goes back through `ExternAllocator` when
+/// it is the global allocator; such nested calls are passed straight to the
+/// system allocator instead.
+struct HostGuard;
+
+impl HostGuard {
+	fn enter() -> Option<Self> {
+		if IN_HOST.replace(true) {
+			None
+		} else {
+			Some(Self)
+		}
+	}
+}
+
+impl Drop for HostGuard {
+	fn drop(&mut self) {
+		IN_HOST.set(false);
+	}
+}
+
+fn track(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+	if !ptr.is_null() {
+		blocks().insert(ptr as usize, MockBlock { size, align, used: true });
+	}
+	ptr
+}
+
+/// # Safety
+/// `align` must be a valid alignment, and `size` must not overflow when
+/// rounded up to it.
+pub(crate) unsafe fn alloc(size: usize, alignment: usize) -> *mut u8 {
+	let layout = Layout::from_size_align_unchecked(size, alignment);
+	let Some(_guard) = HostGuard::enter() else {
+		return System.alloc(layout)
+	};
+	if EXHAUSTED.get() {
+		return ptr::null_mut()
+	}
+	track(System.alloc(layout), size, alignment)
+}
+
+/// # Safety
+/// See [`alloc`].
+#[cfg(feature = "alloc-zeroed")]
+pub(crate) unsafe fn alloc_zeroed(size: usize, alignment: usize) -> *mut u8 {
+	let layout = Layout::from_size_align_unchecked(size, alignment);
+	let Some(_guard) = HostGuard::enter() else {
+		return System.alloc_zeroed(layout)
+	};
+	if EXHAUSTED.get() {
+		return ptr::null_mut()
+	}
+	track(System.alloc_zeroed(layout), size, alignment)
+}
+
+/// Adds `pages` pages of linear memory, which are never freed.
+///
+/// Unlike on `wasm32`, the pages are not contiguous with earlier ones.
+#[cfg(feature = "fallback")]
+pub(crate) fn memory_grow(pages: usize) -> *mut u8 {
+	if EXHAUSTED.get() {
+		return ptr::null_mut()
+	}
+	let page_size = crate::pages::PAGE_SIZE;
+	match Layout::from_size_align(pages * page_size, page_size) {
+		Ok(layout) => unsafe { System.alloc_zeroed(layout) },
+		Err(_) => ptr::null_mut()
+	}
+}
+
+/// Misuse of the `alloc` module, named after the errors `index.js` throws.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Misuse {
+	FreeUnalloced,
+	FreeAgain,
+	FreeMismatchedSize(usize),
+	ReallocUnalloced,
+	ReallocFreed,
+	BlockSizeMismatch(usize)
+}
+
+impl Misuse {
+	fn raise(self, ptr: *mut u8, size: usize, align: usize) -> ! {
+		match self {
+			Self::FreeUnalloced => panic!(
+				"tried to free unallocated memory \
+				(of size {size} with alignment {align}) at {ptr:#x?}"
+			),
+			Self::FreeAgain => panic!(
+				"tried to free again memory \
+				(of size {size} with alignment {align}) at {ptr:#x?}"
+			),
+			Self::FreeMismatchedSize(actual_size) => panic!(
+				"tried to free memory with mismatched size \
+				(tried size {size} with alignment {align}, \
+				though the block was of size {actual_size}) at {ptr:#x?}"
+			),
+			Self::ReallocUnalloced => panic!(
+				"tried to reallocate unallocated memory \
+				(of size {size} with alignment {align}) at {ptr:#x?}"
+			),
+			Self::ReallocFreed => panic!(
+				"tried to reallocate freed memory \
+				(of size {size} with alignment {align}) at {ptr:#x?}"
+			),
+			Self::BlockSizeMismatch(actual_size) => panic!(
+				"encountered memory block size mismatch \
+				(expected size {size} with alignment {align}, \
+				though the block was of size {actual_size}) at {ptr:#x?}"
+			)
+		}
+	}
+}
+
+/// # Safety
+/// `ptr` must have been returned by this mock host.
+pub(crate) unsafe fn dealloc(ptr: *mut u8, size: usize, alignment: usize) {
+	let layout = Layout::from_size_align_unchecked(size, alignment);
+	let Some(guard) = HostGuard::enter() else {
+		return System.dealloc(ptr, layout)
+	};
+
+	let result = match blocks().get_mut(&(ptr as usize)) {
+		None => Err(Misuse::FreeUnalloced),
+		Some(block) if !block.used => Err(Misuse::FreeAgain),
+		Some(block) if block.size != size => {
+			Err(Misuse::FreeMismatchedSize(block.size))
+		}
+		Some(block) => {
+			block.used = false;
+			Ok(())
+		}
+	};
+
+	// Leave the host first, so that the panic message is tracked as well.
+	drop(guard);
+	if let Err(misuse) = result {
+		misuse.raise(ptr, size, alignment)
+	}
+}
+
+/// # Safety
+/// `ptr` must have been returned by this mock host, and `new_size` must not
+/// overflow when rounded up to `alignment`.
+pub(crate) unsafe fn realloc(
+	ptr: *mut u8,
+	size: usize, alignment: usize,
+	new_size: usize
+) -> *mut u8 {
+	let layout = Layout::from_size_align_unchecked(size, alignment);
+	let Some(guard) = HostGuard::enter() else {
+		return System.realloc(ptr, layout, new_size)
+	};
+
+	let mut blocks = blocks();
+	let result = match blocks.get(&(ptr as usize)).copied() {
+		None => Err(Misuse::ReallocUnalloced),
+		Some(block) if !block.used => Err(Misuse::ReallocFreed),
+		Some(block) if block.size != size => {
+			Err(Misuse::BlockSizeMismatch(block.size))
+		}
+		Some(_) if EXHAUSTED.get() => Ok(ptr::null_mut()),
+		Some(block) => {
+			let new_ptr = System.alloc(
+				Layout::from_size_align_unchecked(new_size, alignment)
+			);
+			if !new_ptr.is_null() {
+				new_ptr.copy_from_nonoverlapping(ptr, size.min(new_size));
+				blocks.insert(ptr as usize, MockBlock { used: false, ..block });
+				blocks.insert(new_ptr as usize, MockBlock {
+					size: new_size,
+					align: alignment,
+					used: true
+				});
+			}
+			Ok(new_ptr)
+		}
+	};
+
+	drop(blocks);
+	drop(guard);
+	result.unwrap_or_else(|misuse| misuse.raise(ptr, size, alignment))
+}
+
+/// # Safety
+/// `alignment` must be a valid alignment.
+pub(crate) unsafe fn oom(size: usize, alignment: usize) {
+	// This must not allocate, as the host is out of memory.
+	LAST_OOM.set(Some(Layout::from_size_align_unchecked(size, alignment)));
+}
+
+#[cfg(feature = "abi-check")]
+pub(crate) unsafe fn abi_version() -> u32 {
+	ABI_VERSION.get()
+}
+
+// The location and the final `panic` are only sent by the `wasm32` panic
+// handler.
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) unsafe fn panic() -> ! {
+	let panic = PANIC.take();
+	std::panic::panic_any(panic)
+}
+
+/// # Safety
+/// `file` must point to `len` bytes of UTF-8.
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) unsafe fn panic_put_file(file: *const u8, len: usize) {
+	let file = str_from_raw(file, len);
+	PANIC.with_borrow_mut(|panic| panic.file = Some(file.into()));
+}
+
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) unsafe fn panic_put_line_column(line: usize, col: usize) {
+	PANIC.with_borrow_mut(|panic| {
+		panic.line = line;
+		panic.column = col;
+	});
+}
+
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) unsafe fn panic_put_payload_kind(kind: u32) {
+	PANIC.with_borrow_mut(|panic| panic.payload_kind = kind);
+}
+
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) unsafe fn panic_put_abort_reason(reason: u32) {
+	PANIC.with_borrow_mut(|panic| panic.abort_reason = reason);
+}
+
+/// # Safety
+/// `ch` must be a valid `char`.
+#[cfg(feature = "panic-handler")]
+pub(crate) unsafe fn panic_ch(ch: u32) {
+	let ch = char::from_u32_unchecked(ch);
+	PANIC.with_borrow_mut(|panic| panic.message.push(ch));
+}
+
+/// # Safety
+/// `str` must point to `len` bytes of UTF-8.
+#[cfg(feature = "panic-handler")]
+pub(crate) unsafe fn panic_str(str: *const u8, len: usize) {
+	let str = str_from_raw(str, len);
+	PANIC.with_borrow_mut(|panic| panic.message.push_str(str));
+}
+
+/// # Safety
+/// `file` must point to `len` bytes of UTF-8.
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) unsafe fn panic_nested(file: *const u8, len: usize, line: u32) -> ! {
+	std::panic::panic_any(WasmPanic {
+		message: "panicked while panicking".into(),
+		file: Some(str_from_raw(file, len).into()),
+		line: line as usize,
+		column: 0,
+		payload_kind: 0,
+		abort_reason: 0
+	})
+}
+
+/// # Safety
+/// `report` must point to a valid report.
+#[cfg(feature = "panic-report")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) unsafe fn panic_report(report: *const crate::PanicReport) -> ! {
+	let report = &*report;
+	let panic = WasmPanic {
+		message: str_from_raw(report.message, report.message_len).into(),
+		file: (!report.file.is_null())
+			.then(|| str_from_raw(report.file, report.file_len).into()),
+		line: report.line as usize,
+		column: report.column as usize,
+		payload_kind: report.payload_kind as u32,
+		abort_reason: report.abort_reason as u32
+	};
+	std::panic::panic_any(panic)
+}
+
+/// # Safety
+/// `ch` must be a valid `char`.
+#[cfg(feature = "debug-log")]
+pub(crate) unsafe fn dblog_ch(ch: u32) {
+	let ch = char::from_u32_unchecked(ch);
+	DBLOG_WRITES.set(DBLOG_WRITES.get() + 1);
+	DBLOG.with_borrow_mut(|(pending, _)| pending.push(ch));
+}
+
+/// # Safety
+/// `ptr` must point to `len` bytes of UTF-8.
+#[cfg(feature = "debug-log")]
+pub(crate) unsafe fn dblog_str(ptr: *const u8, len: usize) {
+	let str = str_from_raw(ptr, len);
+	DBLOG_WRITES.set(DBLOG_WRITES.get() + 1);
+	DBLOG.with_borrow_mut(|(pending, _)| pending.push_str(str));
+}
+
+#[cfg(feature = "debug-log")]
+pub(crate) unsafe fn dblog_flush() {
+	DBLOG.with_borrow_mut(|(pending, flushed)| {
+		flushed.push(core::mem::take(pending));
+	});
+}
+
+/// # Safety
+/// `ptr` must point to `len` bytes of UTF-8.
+#[cfg(feature = "debug-log")]
+pub(crate) unsafe fn dblog_write(stream: u32, ptr: *const u8, len: usize) {
+	let str = str_from_raw(ptr, len);
+	STREAMS.with_borrow_mut(|streams| {
+		streams.entry(stream).or_default().0.push_str(str);
+	});
+}
+
+#[cfg(feature = "debug-log")]
+pub(crate) unsafe fn dblog_flush_stream(stream: u32) {
+	STREAMS.with_borrow_mut(|streams| {
+		let (pending, flushed) = streams.entry(stream).or_default();
+		flushed.push(core::mem::take(pending));
+	});
+}
+
+/// # Safety
+/// Each pointer must be null or point to as many bytes of UTF-8 as its length.
+#[cfg(feature = "log")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) unsafe fn dblog_record(
+	level: u32,
+	target: *const u8, target_len: usize,
+	module_path: *const u8, module_path_len: usize,
+	file: *const u8, file_len: usize,
+	line: u32
+) {
+	let optional_str = |ptr: *const u8, len| {
+		(!ptr.is_null()).then(|| String::from(str_from_raw(ptr, len)))
+	};
+
+	let record = DebugRecord {
+		level,
+		target: str_from_raw(target, target_len).into(),
+		module_path: optional_str(module_path, module_path_len),
+		file: optional_str(file, file_len),
+		line,
+		message: DBLOG.with_borrow_mut(|(pending, _)| core::mem::take(pending))
+	};
+	DBLOG_RECORDS.with_borrow_mut(|records| records.push(record));
+}
@@ -0,0 +1,31 @@
+//! Host imports.
+//!
+//! On `wasm32` these are the `alloc`, `panic` and `debug` import modules.
+//! Elsewhere they are served by [`mock`], so that the crate can be tested
+//! natively.
+//!
+//! With the `wasi` feature, the `panic` and `debug` modules are implemented in
+//! the guest over WASI instead, see [`wasi`].
+
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+#[cfg(target_arch = "wasm32")]
+pub use wasm::*;
+
+#[cfg(all(
+	target_arch = "wasm32",
+	feature = "wasi",
+	any(feature = "panic-handler", feature = "debug-log")
+))]
+mod wasi;
+#[cfg(all(
+	target_arch = "wasm32",
+	feature = "wasi",
+	any(feature = "panic-handler", feature = "debug-log")
+))]
+pub use wasi::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mock;
+#[cfg(not(target_arch = "wasm32"))]
+pub use mock::*;
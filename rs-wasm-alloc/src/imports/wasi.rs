@@ -0,0 +1,233 @@
+//! `panic` and `debug` import modules implemented in the guest over WASI, with
+//! the `wasi` feature.
+//!
+//! Output is written to `stdout` and `stderr` through
+//! `wasi_snapshot_preview1.fd_write`, formatted like the native host does.
+//! Panics end the process through `proc_exit` with the exit code of Rust's
+//! panics; their payload kind and abort reason are not printed.
+
+use core::fmt::{
+	self,
+	Write
+};
+#[cfg(feature = "debug-log")]
+use core::slice;
+
+use crate::lock::Lock;
+use crate::text::{
+	str_from_raw,
+	Truncating
+};
+
+#[link(wasm_import_module = "wasi_snapshot_preview1")]
+extern "C" {
+	fn fd_write(fd: u32, iovs: *const Ciovec, iovs_len: usize, nwritten: *mut usize) -> u32;
+	#[cfg(feature = "panic-handler")]
+	fn proc_exit(code: u32) -> !;
+}
+
+#[repr(C)]
+struct Ciovec {
+	buf: *const u8,
+	len: usize
+}
+
+const STDERR: u32 = 2;
+
+#[cfg(feature = "panic-handler")]
+const PANIC_EXIT_CODE: u32 = 101;
+
+/// Capacity of the pending debug message and panic message. Longer messages
+/// are truncated.
+const LINE_CAPACITY: usize = 1024;
+
+/// Writes all of `bytes` to `fd`, giving up on the first error.
+fn write_all(fd: u32, mut bytes: &[u8]) {
+	while !bytes.is_empty() {
+		let iov = Ciovec {
+			buf: bytes.as_ptr(),
+			len: bytes.len()
+		};
+		let mut written = 0;
+		if unsafe { fd_write(fd, &iov, 1, &mut written) } != 0 || written == 0 { return }
+		bytes = &bytes[written..];
+	}
+}
+
+/// Writer to a file descriptor.
+struct Fd(u32);
+
+impl Write for Fd {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		write_all(self.0, s.as_bytes());
+		Ok(())
+	}
+}
+
+fn char_from_u32(ch: u32) -> char {
+	char::from_u32(ch).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+/// Message pending until it is flushed, truncated at a `char` boundary once
+/// it is full.
+type Line = Truncating<[u8; LINE_CAPACITY]>;
+
+#[cfg(feature = "panic-handler")]
+struct PanicState {
+	message: Line,
+	file: Option<&'static str>,
+	line: usize,
+	column: usize
+}
+
+#[cfg(feature = "panic-handler")]
+static PANIC: Lock<PanicState> = Lock::new(PanicState {
+	message: Line::new([0; LINE_CAPACITY]),
+	file: None,
+	line: 0,
+	column: 0
+});
+
+/// Prints a panic to `stderr` and exits.
+#[cfg(feature = "panic-handler")]
+fn exit_with_panic(message: &str, location: Option<(&str, usize, usize)>) -> ! {
+	let mut stderr = crate::Buffered::<_, 256>::new(Fd(STDERR));
+	let _ = match location {
+		Some((file, line, column)) => {
+			writeln!(stderr, "panicked at {file}:{line}:{column}:\n{message}")
+		},
+		None => writeln!(stderr, "panicked:\n{message}")
+	};
+	stderr.send();
+	unsafe { proc_exit(PANIC_EXIT_CODE) }
+}
+
+/// # Safety
+/// The panic must not be finished already.
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(any(test, feature = "panic-report"), allow(dead_code))]
+pub unsafe fn panic() -> ! {
+	let panic = PANIC.lock();
+	let location = panic.file.map(|file| (file, panic.line, panic.column));
+	exit_with_panic(panic.message.as_str(), location)
+}
+
+/// # Safety
+/// `file` must point to `len` bytes of UTF-8 that live until the end.
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(any(test, feature = "panic-report"), allow(dead_code))]
+pub unsafe fn panic_put_file(file: *const u8, len: usize) {
+	PANIC.lock().file = Some(str_from_raw(file, len));
+}
+
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(any(test, feature = "panic-report"), allow(dead_code))]
+pub unsafe fn panic_put_line_column(line: usize, col: usize) {
+	let mut panic = PANIC.lock();
+	panic.line = line;
+	panic.column = col;
+}
+
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(any(test, feature = "panic-report"), allow(dead_code))]
+pub unsafe fn panic_put_payload_kind(_kind: u32) {}
+
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(any(test, feature = "panic-report"), allow(dead_code))]
+pub unsafe fn panic_put_abort_reason(_reason: u32) {}
+
+#[cfg(feature = "panic-handler")]
+pub unsafe fn panic_ch(ch: u32) {
+	let _ = PANIC.lock().message.write_char(char_from_u32(ch));
+}
+
+/// # Safety
+/// `str` must point to `len` bytes of UTF-8.
+#[cfg(feature = "panic-handler")]
+pub unsafe fn panic_str(str: *const u8, len: usize) {
+	let _ = PANIC.lock().message.write_str(str_from_raw(str, len));
+}
+
+/// # Safety
+/// `file` must point to `len` bytes of UTF-8.
+#[cfg(feature = "panic-handler")]
+#[cfg_attr(test, allow(dead_code))]
+pub unsafe fn panic_nested(file: *const u8, len: usize, line: u32) -> ! {
+	let file = str_from_raw(file, len);
+	let _ = writeln!(Fd(STDERR), "panicked while panicking at {file}:{line}");
+	proc_exit(PANIC_EXIT_CODE)
+}
+
+/// # Safety
+/// The strings of `report` must be valid.
+#[cfg(feature = "panic-report")]
+#[cfg_attr(test, allow(dead_code))]
+pub unsafe fn panic_report(report: *const crate::PanicReport) -> ! {
+	let report = &*report;
+	let message = str_from_raw(report.message, report.message_len);
+	let location = (!report.file.is_null()).then(|| (
+		str_from_raw(report.file, report.file_len),
+		report.line as usize,
+		report.column as usize
+	));
+	exit_with_panic(message, location)
+}
+
+#[cfg(feature = "debug-log")]
+static DEBUG: Lock<Line> = Lock::new(Line::new([0; LINE_CAPACITY]));
+
+#[cfg(feature = "debug-log")]
+pub unsafe fn dblog_ch(ch: u32) {
+	let _ = DEBUG.lock().write_char(char_from_u32(ch));
+}
+
+/// # Safety
+/// `ptr` must point to `len` bytes of UTF-8.
+#[cfg(feature = "debug-log")]
+pub unsafe fn dblog_str(ptr: *const u8, len: usize) {
+	let _ = DEBUG.lock().write_str(str_from_raw(ptr, len));
+}
+
+/// Writes the pending message to `stderr`.
+#[cfg(feature = "debug-log")]
+pub unsafe fn dblog_flush() {
+	let mut debug = DEBUG.lock();
+	let _ = writeln!(Fd(STDERR), "{}", debug.as_str());
+	debug.clear();
+}
+
+/// # Safety
+/// `ptr` must point to `len` bytes.
+#[cfg(feature = "debug-log")]
+pub unsafe fn dblog_write(stream: u32, ptr: *const u8, len: usize) {
+	write_all(stream, slice::from_raw_parts(ptr, len));
+}
+
+#[cfg(feature = "debug-log")]
+pub unsafe fn dblog_flush_stream(stream: u32) {
+	write_all(stream, b"\n");
+}
+
+/// Writes the pending message to `stderr` as a record of the `log` crate.
+///
+/// # Safety
+/// `target` must point to `target_len` bytes of UTF-8.
+#[cfg(feature = "log")]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn dblog_record(
+	level: u32,
+	target: *const u8, target_len: usize,
+	_module_path: *const u8, _module_path_len: usize,
+	_file: *const u8, _file_len: usize,
+	_line: u32
+) {
+	const LEVELS: [&str; 6] = ["?", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+	let level = LEVELS.get(level as usize).unwrap_or(&LEVELS[0]);
+	let target = str_from_raw(target, target_len);
+	let mut debug = DEBUG.lock();
+	let mut stderr = crate::Buffered::<_, 256>::new(Fd(STDERR));
+	let _ = writeln!(stderr, "[{level} {target}] {}", debug.as_str());
+	stderr.send();
+	debug.clear();
+}
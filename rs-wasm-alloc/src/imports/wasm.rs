@@ -0,0 +1,51 @@
+//! Import modules provided by the WebAssembly host.
+
+#[link(wasm_import_module = "alloc")]
+extern "C" {
+	pub fn alloc(size: usize, alignment: usize) -> *mut u8;
+	#[cfg(feature = "alloc-zeroed")]
+	pub fn alloc_zeroed(size: usize, alignment: usize) -> *mut u8;
+	pub fn dealloc(ptr: *mut u8, size: usize, alignment: usize);
+	pub fn realloc(
+		ptr: *mut u8,
+		size: usize, alignment: usize,
+		new_size: usize
+	) -> *mut u8;
+	pub fn oom(size: usize, alignment: usize);
+	#[cfg(feature = "abi-check")]
+	pub fn abi_version() -> u32;
+}
+
+#[cfg(all(feature = "panic-handler", not(feature = "wasi")))]
+#[cfg_attr(any(test, feature = "panic-report"), allow(dead_code))]
+#[link(wasm_import_module = "panic")]
+extern "C" {
+	pub fn panic() -> !;
+	pub fn panic_put_file(file: *const u8, len: usize);
+	pub fn panic_put_line_column(line: usize, col: usize);
+	pub fn panic_put_payload_kind(kind: u32);
+	pub fn panic_put_abort_reason(reason: u32);
+	pub fn panic_ch(ch: u32);
+	pub fn panic_str(str: *const u8, len: usize);
+	pub fn panic_nested(file: *const u8, len: usize, line: u32) -> !;
+	#[cfg(feature = "panic-report")]
+	pub fn panic_report(report: *const crate::PanicReport) -> !;
+}
+
+#[cfg(all(feature = "debug-log", not(feature = "wasi")))]
+#[link(wasm_import_module = "debug")]
+extern "C" {
+	pub fn dblog_ch(ch: u32);
+	pub fn dblog_str(ptr: *const u8, len: usize);
+	pub fn dblog_flush();
+	pub fn dblog_write(stream: u32, ptr: *const u8, len: usize);
+	pub fn dblog_flush_stream(stream: u32);
+	#[cfg(feature = "log")]
+	pub fn dblog_record(
+		level: u32,
+		target: *const u8, target_len: usize,
+		module_path: *const u8, module_path_len: usize,
+		file: *const u8, file_len: usize,
+		line: u32
+	);
+}
@@ -0,0 +1,175 @@
+//! Tracking of live allocations, to report leaks.
+//!
+//! Every block handed out by [`ExternAllocator`](crate::ExternAllocator) is
+//! recorded in a fixed-size table in the guest, which [`dump`] writes to the
+//! host's debug log. Lookups are linear, so this is meant for debugging only.
+
+use core::fmt::Write;
+
+use crate::lock::Lock;
+use crate::{
+	Buffered,
+	DebugLog
+};
+
+/// Number of live allocations that can be tracked at once.
+pub const CAPACITY: usize = 1024;
+
+/// Allocation that has not been freed yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveAlloc {
+	pub ptr: usize,
+	pub size: usize,
+	pub align: usize,
+	/// Tag that was current when the block was allocated.
+	pub tag: Option<&'static str>
+}
+
+struct Table {
+	allocs: [Option<LiveAlloc>; CAPACITY],
+	len: usize,
+	/// Allocations that did not fit in the table.
+	untracked: usize,
+	tag: Option<&'static str>
+}
+
+static TABLE: Lock<Table> = Lock::new(Table {
+	allocs: [None; CAPACITY],
+	len: 0,
+	untracked: 0,
+	tag: None
+});
+
+/// Sets the tag to record with the following allocations, returning the
+/// previous one.
+pub fn set_tag(tag: Option<&'static str>) -> Option<&'static str> {
+	core::mem::replace(&mut TABLE.lock().tag, tag)
+}
+
+/// Calls `f` with every live allocation, in no particular order.
+///
+/// `f` must not allocate with `ExternAllocator`.
+pub fn for_each(mut f: impl FnMut(&LiveAlloc)) {
+	let table = TABLE.lock();
+	table.allocs[..table.len].iter().flatten().for_each(&mut f);
+}
+
+/// Writes every live allocation to the host's debug log.
+pub fn dump() {
+	let table = TABLE.lock();
+	let mut log = Buffered::<_, 256>::new(DebugLog::new());
+	let _ = writeln!(log, "{} live allocations:", table.len);
+	for alloc in table.allocs[..table.len].iter().flatten() {
+		let _ = write!(
+			log,
+			"  {} bytes aligned to {} at {:#x}",
+			alloc.size, alloc.align, alloc.ptr
+		);
+		if let Some(tag) = alloc.tag {
+			let _ = write!(log, " ({tag})");
+		}
+		let _ = writeln!(log);
+	}
+	if table.untracked > 0 {
+		let _ = writeln!(
+			log,
+			"{} allocations did not fit in the table", table.untracked
+		);
+	}
+	log.flush();
+}
+
+/// Export of [`dump`].
+#[export_name = "__rs_wasm_alloc_dump_leaks"]
+pub extern "C" fn export_dump() {
+	dump()
+}
+
+pub(crate) fn record_alloc(ptr: *mut u8, size: usize, align: usize) {
+	if ptr.is_null() { return }
+
+	let mut table = TABLE.lock();
+	if table.len == CAPACITY {
+		table.untracked += 1;
+		return
+	}
+
+	let len = table.len;
+	let tag = table.tag;
+	table.allocs[len] = Some(LiveAlloc {
+		ptr: ptr as usize,
+		size,
+		align,
+		tag
+	});
+	table.len += 1;
+}
+
+fn position(table: &Table, ptr: *mut u8) -> Option<usize> {
+	table.allocs[..table.len].iter()
+		.position(move |alloc| alloc.is_some_and(|alloc| alloc.ptr == ptr as usize))
+}
+
+pub(crate) fn record_dealloc(ptr: *mut u8) {
+	let mut table = TABLE.lock();
+	match position(&table, ptr) {
+		Some(i) => {
+			let last = table.len - 1;
+			table.allocs.swap(i, last);
+			table.allocs[last] = None;
+			table.len = last;
+		}
+		None => table.untracked = table.untracked.saturating_sub(1)
+	}
+}
+
+pub(crate) fn record_realloc(ptr: *mut u8, new_ptr: *mut u8, new_size: usize) {
+	if new_ptr.is_null() { return }
+
+	let mut table = TABLE.lock();
+	if let Some(i) = position(&table, ptr) {
+		if let Some(alloc) = &mut table.allocs[i] {
+			alloc.ptr = new_ptr as usize;
+			alloc.size = new_size;
+		}
+	}
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use core::alloc::{
+		GlobalAlloc,
+		Layout
+	};
+
+	use super::*;
+	use crate::ExternAllocator;
+
+	fn find(ptr: *mut u8) -> Option<LiveAlloc> {
+		let mut found = None;
+		for_each(|alloc| if alloc.ptr == ptr as usize { found = Some(*alloc) });
+		found
+	}
+
+	#[test]
+	fn live_allocations_are_tracked_with_tags() {
+		let layout = Layout::from_size_align(12, 4).unwrap();
+		unsafe {
+			let previous = set_tag(Some("leaks test"));
+			let ptr = ExternAllocator.alloc(layout);
+			set_tag(previous);
+			assert_eq!(find(ptr), Some(LiveAlloc {
+				ptr: ptr as usize,
+				size: 12,
+				align: 4,
+				tag: Some("leaks test")
+			}));
+
+			let new_ptr = ExternAllocator.realloc(ptr, layout, 20);
+			assert_eq!(find(new_ptr).map(|alloc| alloc.size), Some(20));
+
+			ExternAllocator.dealloc(new_ptr, Layout::from_size_align(20, 4).unwrap());
+			assert_eq!(find(new_ptr), None);
+		}
+	}
+}
@@ -1,86 +1,186 @@
 //! External allocator.
 
 #![no_std]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
-extern crate alloc;
-
-use alloc::alloc::{
+use core::alloc::{
 	GlobalAlloc,
 	Layout
 };
-use core::fmt::{
-	self,
-	Write
+
+mod imports;
+pub mod abi;
+#[cfg(any(
+	feature = "leak-tracking",
+	feature = "fallback",
+	feature = "hybrid",
+	feature = "free-cache",
+	all(
+		feature = "wasi",
+		target_arch = "wasm32",
+		any(feature = "panic-handler", feature = "debug-log")
+	)
+))]
+mod lock;
+#[cfg(not(target_arch = "wasm32"))]
+pub use imports::mock;
+#[cfg(any(feature = "fallback", feature = "hybrid", feature = "free-cache"))]
+mod blocks;
+#[cfg(any(feature = "panic-handler", feature = "debug-log"))]
+mod text;
+
+mod buffered;
+pub use buffered::Buffered;
+
+#[doc(hidden)]
+pub mod define;
+
+#[cfg(feature = "debug-log")]
+mod debug;
+#[cfg(feature = "debug-log")]
+pub use debug::{
+	DebugImports,
+	DebugLog,
+	HostDebug
 };
-use core::panic::PanicInfo;
-
-#[link(wasm_import_module = "alloc")]
-extern "C" {
-	fn alloc(size: usize, alignment: usize) -> *mut u8;
-	fn dealloc(ptr: *mut u8, size: usize, alignment: usize);
-	fn realloc(
-		ptr: *mut u8,
-		size: usize, alignment: usize,
-		new_size: usize
-	) -> *mut u8;
-}
 
-#[panic_handler]
-pub fn panic_handler(info: &PanicInfo) -> ! {
-	#[link(wasm_import_module = "panic")]
-	extern "C" {
-		fn panic() -> !;
-		fn panic_put_file(file: *const u8, len: usize);
-		fn panic_put_line_column(line: usize, col: usize);
-		fn panic_ch(ch: u32);
-		fn panic_str(str: *const u8, len: usize);
-	}
+#[cfg(feature = "debug-log")]
+pub mod print;
 
-	#[derive(Debug)]
-	pub struct Panic;
-	
-	impl Write for Panic {
-		fn write_char(&mut self, ch: char) -> fmt::Result {
-			unsafe { panic_ch(ch as u32) };
-			Ok(())
-		}
-	
-		fn write_str(&mut self, s: &str) -> fmt::Result {
-			unsafe { panic_str(s.as_ptr(), s.len()) };
-			Ok(())
-		}
-	}
+#[cfg(feature = "log")]
+pub mod logger;
 
-	if let Some(message) = info.payload().downcast_ref::<&'static str>() {
-		let _ = write!(Panic, "{}", message);
-	} else {
-		let _ = write!(Panic, "{}", info);
-	}
+mod abort;
+pub use abort::{
+	abort,
+	set_abort_reason,
+	AbortReason,
+	PayloadKind
+};
 
-	if let Some(location) = info.location() {
-		unsafe {
-			let file = location.file();
-			panic_put_file(file.as_ptr(), file.len());
-			panic_put_line_column(
-				location.line() as usize,
-				location.column() as usize
-			);
-		};
-	}
+mod panic;
+pub use panic::{
+	panicking,
+	set_panic_hook,
+	take_panic_hook,
+	PanicHook
+};
+#[cfg(feature = "panic-handler")]
+pub use panic::Panic;
+#[cfg(feature = "panic-report")]
+pub use panic::{
+	PanicReport,
+	REPORT_CAPACITY
+};
 
-	unsafe { panic() }
-}
+mod pages;
+#[cfg(all(feature = "memory-exports", target_arch = "wasm32"))]
+pub mod memory;
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+pub use stats::AllocStats;
+
+#[cfg(feature = "leak-tracking")]
+pub mod leaks;
+
+#[cfg(feature = "nightly")]
+mod allocator;
+
+#[cfg(feature = "fallback")]
+pub mod fallback;
+#[cfg(feature = "fallback")]
+pub use fallback::FallbackAllocator;
 
+#[cfg(feature = "hybrid")]
+pub mod hybrid;
+#[cfg(feature = "hybrid")]
+pub use hybrid::HybridAllocator;
+
+#[cfg(feature = "free-cache")]
+pub mod cache;
+#[cfg(feature = "free-cache")]
+pub use cache::{
+	CacheLimits,
+	CachedAllocator
+};
+
+/// Allocator that forwards every request to the host's `alloc` import module.
+///
+/// The host returns null when it runs out of memory. The failed layout is then
+/// reported back through `alloc.oom` before null is passed on, which makes
+/// infallible allocations end up in [`handle_alloc_error`].
+///
+/// Zeroed blocks come from `alloc.alloc_zeroed` with the `alloc-zeroed`
+/// feature. Without it, they come from `alloc` and are zeroed in the guest.
+///
+/// With the `fallback` feature, `fallback::enable` makes it allocate from a
+/// `FallbackAllocator` instead, which is neither reported to the host nor
+/// counted.
+///
+/// With the `nightly` feature, it also implements [`Allocator`], so that
+/// collections like `Vec::new_in(ExternAllocator)` can use the host while the
+/// global allocator is another one.
+///
+/// [`handle_alloc_error`]: https://doc.rust-lang.org/alloc/alloc/fn.handle_alloc_error.html
+/// [`Allocator`]: https://doc.rust-lang.org/core/alloc/trait.Allocator.html
 #[derive(Debug, PartialEq, Eq)]
 pub struct ExternAllocator;
 
+impl ExternAllocator {
+	#[inline]
+	unsafe fn check(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+		if ptr.is_null() {
+			define::record_oom();
+			imports::oom(size, align);
+		}
+		ptr
+	}
+
+	/// Returns the statistics of every `ExternAllocator` in the guest.
+	#[cfg(feature = "stats")]
+	#[inline]
+	pub fn stats() -> AllocStats {
+		AllocStats::get()
+	}
+}
+
 unsafe impl GlobalAlloc for ExternAllocator {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-		alloc(layout.size(), layout.align())
+		#[cfg(feature = "fallback")]
+		if fallback::enabled() {
+			return FallbackAllocator.alloc(layout)
+		}
+		#[cfg(feature = "abi-check")]
+		abi::check_once();
+		let (size, align) = (layout.size(), layout.align());
+		let ptr = Self::check(imports::alloc(size, align), size, align);
+		define::record_alloc(ptr, size, align);
+		ptr
+	}
+
+	#[cfg(feature = "alloc-zeroed")]
+	unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+		#[cfg(feature = "fallback")]
+		if fallback::enabled() {
+			return FallbackAllocator.alloc_zeroed(layout)
+		}
+		#[cfg(feature = "abi-check")]
+		abi::check_once();
+		let (size, align) = (layout.size(), layout.align());
+		let ptr = Self::check(imports::alloc_zeroed(size, align), size, align);
+		define::record_alloc(ptr, size, align);
+		ptr
 	}
 
 	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-		dealloc(ptr, layout.size(), layout.align())
+		#[cfg(feature = "fallback")]
+		if fallback::enabled() {
+			return FallbackAllocator.dealloc(ptr, layout)
+		}
+		imports::dealloc(ptr, layout.size(), layout.align());
+		define::record_dealloc(ptr, layout.size());
 	}
 
 	unsafe fn realloc(
@@ -88,68 +188,135 @@ unsafe impl GlobalAlloc for ExternAllocator {
 		ptr: *mut u8, layout: Layout,
 		new_size: usize
 	) -> *mut u8 {
-		realloc(ptr, layout.size(), layout.align(), new_size)
+		#[cfg(feature = "fallback")]
+		if fallback::enabled() {
+			return FallbackAllocator.realloc(ptr, layout, new_size)
+		}
+		let (size, align) = (layout.size(), layout.align());
+		let new_ptr = Self::check(
+			imports::realloc(ptr, size, align, new_size),
+			new_size, align
+		);
+		define::record_realloc(ptr, new_ptr, size, new_size);
+		new_ptr
 	}
 }
 
+#[cfg(feature = "global-allocator")]
 #[global_allocator]
-pub static mut GLOBAL_ALLOCATOR: ExternAllocator = ExternAllocator;
-
-#[export_name = "run"]
-pub extern "C" fn run() {
-	use alloc::string::String;
-	use alloc::vec::Vec;
-
-	#[allow(dead_code)]
-	#[derive(Debug)]
-	struct User {
-		id: usize,
-		name: String
-	}
+pub static GLOBAL_ALLOCATOR: ExternAllocator = ExternAllocator;
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use super::*;
+
+	const LAYOUT: Layout = match Layout::from_size_align(24, 8) {
+		Ok(layout) => layout,
+		Err(_) => unreachable!()
+	};
+
+	#[test]
+	fn blocks_are_tracked_by_the_host() {
+		unsafe {
+			let ptr = ExternAllocator.alloc(LAYOUT);
+			assert_eq!(mock::block(ptr), Some(mock::MockBlock {
+				size: 24,
+				align: 8,
+				used: true
+			}));
 
-	let _: String = "taketwo".into();
+			let new_ptr = ExternAllocator.realloc(ptr, LAYOUT, 64);
+			assert_eq!(mock::block(new_ptr).map(|block| block.size), Some(64));
 
-	let mut stuff = Vec::new();
-	for _ in 0..100 {
-		stuff.push(42);
+			ExternAllocator.dealloc(new_ptr, Layout::from_size_align(64, 8).unwrap());
+		}
 	}
-	
-	#[link(wasm_import_module = "debug")]
-	extern "C" {
-		fn dblog_ch(ch: u32);
-		fn dblog_str(ptr: *const u8, len: usize);
-		fn dblog_flush();
+
+	#[test]
+	fn realloc_preserves_contents() {
+		unsafe {
+			let ptr = ExternAllocator.alloc(LAYOUT);
+			ptr.write_bytes(0xAB, LAYOUT.size());
+			let new_ptr = ExternAllocator.realloc(ptr, LAYOUT, 4096);
+			assert!((0..LAYOUT.size()).all(|i| *new_ptr.add(i) == 0xAB));
+			ExternAllocator.dealloc(new_ptr, Layout::from_size_align(4096, 8).unwrap());
+		}
 	}
-	
-	#[derive(Debug)]
-	pub struct DebugLog;
-
-	impl DebugLog {
-		#[inline]
-		pub fn flush(&mut self) {
-			unsafe { dblog_flush() }
+
+	#[test]
+	fn alloc_zeroed_is_zeroed() {
+		unsafe {
+			let layout = Layout::from_size_align(256, 16).unwrap();
+			let ptr = ExternAllocator.alloc_zeroed(layout);
+			assert!((0..layout.size()).all(|i| *ptr.add(i) == 0));
+			ExternAllocator.dealloc(ptr, layout);
 		}
 	}
-	
-	impl Write for DebugLog {
-		fn write_char(&mut self, ch: char) -> fmt::Result {
-			unsafe { dblog_ch(ch as u32) };
-			Ok(())
+
+	#[test]
+	fn exhaustion_is_reported_to_the_host() {
+		unsafe {
+			let ptr = ExternAllocator.alloc(LAYOUT);
+			mock::set_exhausted(true);
+			let null = ExternAllocator.alloc(LAYOUT);
+			let alloc_oom = mock::take_last_oom();
+			let new_ptr = ExternAllocator.realloc(ptr, LAYOUT, 48);
+			let realloc_oom = mock::take_last_oom();
+			mock::set_exhausted(false);
+
+			assert!(null.is_null() && new_ptr.is_null());
+			assert_eq!(alloc_oom, Some(LAYOUT));
+			assert_eq!(realloc_oom, Layout::from_size_align(48, 8).ok());
+			ExternAllocator.dealloc(ptr, LAYOUT);
 		}
-	
-		fn write_str(&mut self, s: &str) -> fmt::Result {
-			unsafe { dblog_str(s.as_ptr(), s.len()) };
-			Ok(())
+	}
+
+	#[cfg(feature = "stats")]
+	#[test]
+	fn stats_count_every_call() {
+		// Other tests allocate concurrently, so only lower bounds are checked.
+		let before = ExternAllocator::stats();
+		unsafe {
+			let ptr = ExternAllocator.alloc(LAYOUT);
+			let during = ExternAllocator::stats();
+			let new_ptr = ExternAllocator.realloc(ptr, LAYOUT, 40);
+			ExternAllocator.dealloc(new_ptr, Layout::from_size_align(40, 8).unwrap());
+
+			let after = ExternAllocator::stats();
+			assert!(during.peak_bytes >= during.live_bytes);
+			assert!(after.allocs > before.allocs);
+			assert!(after.reallocs > before.reallocs);
+			assert!(after.frees > before.frees);
+			assert!(after.host_calls >= before.host_calls + 3);
 		}
 	}
 
-	let user = User {
-		id: 1337,
-		name: "track one".into()
-	};
+	#[test]
+	#[should_panic(expected = "tried to free again")]
+	fn double_free_panics() {
+		unsafe {
+			let ptr = ExternAllocator.alloc(LAYOUT);
+			ExternAllocator.dealloc(ptr, LAYOUT);
+			ExternAllocator.dealloc(ptr, LAYOUT);
+		}
+	}
+
+	#[test]
+	#[should_panic(expected = "tried to free memory with mismatched size")]
+	fn free_with_mismatched_size_panics() {
+		unsafe {
+			let ptr = ExternAllocator.alloc(LAYOUT);
+			ExternAllocator.dealloc(ptr, Layout::from_size_align(8, 8).unwrap());
+		}
+	}
 
-	let mut user_info = String::new();
-	user_info.push_str(&user.name);
-	let _ = writeln!(DebugLog, "{}", user_info);
-	DebugLog.flush();
+	#[test]
+	#[should_panic(expected = "tried to reallocate freed memory")]
+	fn realloc_after_free_panics() {
+		unsafe {
+			let ptr = ExternAllocator.alloc(LAYOUT);
+			ExternAllocator.dealloc(ptr, LAYOUT);
+			ExternAllocator.realloc(ptr, LAYOUT, 32);
+		}
+	}
 }
@@ -0,0 +1,64 @@
+//! Minimal spin lock for guest-side state.
+//!
+//! WebAssembly guests are mostly single-threaded, so the lock is practically
+//! never contended; it mostly keeps the state sound on native targets.
+
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ops::{
+	Deref,
+	DerefMut
+};
+use core::sync::atomic::{
+	AtomicBool,
+	Ordering
+};
+
+pub(crate) struct Lock<T> {
+	locked: AtomicBool,
+	value: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for Lock<T> {}
+
+impl<T> Lock<T> {
+	pub const fn new(value: T) -> Self {
+		Self {
+			locked: AtomicBool::new(false),
+			value: UnsafeCell::new(value)
+		}
+	}
+
+	pub fn lock(&self) -> LockGuard<'_, T> {
+		while self.locked.compare_exchange_weak(
+			false, true,
+			Ordering::Acquire, Ordering::Relaxed
+		).is_err() {
+			hint::spin_loop();
+		}
+		LockGuard { lock: self }
+	}
+}
+
+pub(crate) struct LockGuard<'a, T> {
+	lock: &'a Lock<T>
+}
+
+impl<T> Deref for LockGuard<'_, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		unsafe { &*self.lock.value.get() }
+	}
+}
+
+impl<T> DerefMut for LockGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe { &mut *self.lock.value.get() }
+	}
+}
+
+impl<T> Drop for LockGuard<'_, T> {
+	fn drop(&mut self) {
+		self.lock.locked.store(false, Ordering::Release);
+	}
+}
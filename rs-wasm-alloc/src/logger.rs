@@ -0,0 +1,123 @@
+//! [`log`] backend over the host's `debug` module, or any other module that
+//! implements [`DebugImports`].
+
+use core::fmt::Write;
+use core::marker::PhantomData;
+use core::ptr;
+
+use log::{
+	LevelFilter,
+	Log,
+	Metadata,
+	Record,
+	SetLoggerError
+};
+
+use crate::{
+	Buffered,
+	DebugImports,
+	DebugLog,
+	HostDebug
+};
+
+/// Logger that sends every record to the debug log of `I`, the host's `debug`
+/// module by default.
+///
+/// The message is written like with [`DebugLog`], and then passed to the host
+/// with its metadata through `debug.dblog_record`, in place of a flush. The
+/// level is passed as in [`log::Level`], from 1 for errors to 5 for traces.
+#[derive(Debug)]
+pub struct DebugLogger<I = HostDebug>(PhantomData<fn() -> I>);
+
+impl DebugLogger {
+	#[inline]
+	pub const fn new() -> Self {
+		Self(PhantomData)
+	}
+}
+
+impl<I> Default for DebugLogger<I> {
+	fn default() -> Self {
+		Self(PhantomData)
+	}
+}
+
+impl<I: DebugImports> Log for DebugLogger<I> {
+	fn enabled(&self, _: &Metadata<'_>) -> bool {
+		true
+	}
+
+	fn log(&self, record: &Record<'_>) {
+		let mut message = Buffered::<_, 256>::new(DebugLog::<I>::default());
+		let _ = write!(message, "{}", record.args());
+		message.send();
+
+		fn optional_str(str: Option<&str>) -> (*const u8, usize) {
+			str.map_or((ptr::null(), 0), |str| (str.as_ptr(), str.len()))
+		}
+
+		let target = record.target();
+		let (module_path, module_path_len) = optional_str(record.module_path());
+		let (file, file_len) = optional_str(record.file());
+		unsafe {
+			I::dblog_record(
+				record.level() as u32,
+				target.as_ptr(), target.len(),
+				module_path, module_path_len,
+				file, file_len,
+				record.line().unwrap_or(0)
+			)
+		}
+	}
+
+	fn flush(&self) {}
+}
+
+/// Installs [`DebugLogger`] as the logger, logging records up to `level`.
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+	init_with::<HostDebug>(level)
+}
+
+/// Installs [`DebugLogger`] over the debug module of `I` as the logger,
+/// logging records up to `level`.
+pub fn init_with<I: DebugImports + 'static>(level: LevelFilter) -> Result<(), SetLoggerError> {
+	log::set_logger(&DebugLogger::<I>(PhantomData))?;
+	log::set_max_level(level);
+	Ok(())
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	use log::{
+		Level,
+		Log,
+		Record
+	};
+
+	use super::*;
+	use crate::mock::{
+		self,
+		DebugRecord
+	};
+
+	#[test]
+	fn records_carry_their_metadata() {
+		DebugLogger::new().log(&Record::builder()
+			.args(format_args!("hello {}", "world"))
+			.level(Level::Warn)
+			.target("app")
+			.module_path(Some("app::module"))
+			.file(None)
+			.line(Some(7))
+			.build());
+
+		assert_eq!(mock::take_debug_records(), [DebugRecord {
+			level: 2,
+			target: "app".into(),
+			module_path: Some("app::module".into()),
+			file: None,
+			line: 7,
+			message: "hello world".into()
+		}]);
+	}
+}
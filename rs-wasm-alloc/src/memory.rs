@@ -0,0 +1,90 @@
+//! Linear memory layout and growth, exported for the host.
+
+pub use crate::pages::{
+	grow,
+	PAGE_SIZE
+};
+
+// Defined by `wasm-ld`.
+extern "C" {
+	static __data_end: u8;
+	static __heap_base: u8;
+	static __stack_low: u8;
+	static __stack_high: u8;
+}
+
+/// Returns the address where the guest's static data and stack end, and the
+/// heap may begin.
+#[inline]
+pub fn heap_base() -> usize {
+	(&raw const __heap_base) as usize
+}
+
+/// Returns the address where the guest's static data ends.
+#[inline]
+pub fn data_end() -> usize {
+	(&raw const __data_end) as usize
+}
+
+/// Returns the lowest address of the guest's stack.
+#[inline]
+pub fn stack_low() -> usize {
+	(&raw const __stack_low) as usize
+}
+
+/// Returns the address right past the highest address of the guest's stack,
+/// where the stack pointer starts.
+#[inline]
+pub fn stack_high() -> usize {
+	(&raw const __stack_high) as usize
+}
+
+/// Layout of the regions of linear memory that the guest uses statically.
+///
+/// Everything from [`heap_base`](Self::heap_base) on is free for the host to
+/// place blocks in.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+	pub stack_low: usize,
+	pub stack_high: usize,
+	pub data_end: usize,
+	pub heap_base: usize
+}
+
+impl MemoryLayout {
+	#[inline]
+	pub fn get() -> Self {
+		Self {
+			stack_low: stack_low(),
+			stack_high: stack_high(),
+			data_end: data_end(),
+			heap_base: heap_base()
+		}
+	}
+}
+
+#[export_name = "__rs_wasm_alloc_heap_base"]
+pub extern "C" fn export_heap_base() -> usize {
+	heap_base()
+}
+
+#[export_name = "__rs_wasm_alloc_data_end"]
+pub extern "C" fn export_data_end() -> usize {
+	data_end()
+}
+
+/// Export of [`MemoryLayout::get`], writing the layout to `layout`.
+///
+/// # Safety
+/// `layout` must be valid for writing a [`MemoryLayout`].
+#[export_name = "__rs_wasm_alloc_memory_layout"]
+pub unsafe extern "C" fn export_memory_layout(layout: *mut MemoryLayout) {
+	layout.write(MemoryLayout::get())
+}
+
+/// Export of [`grow`], returning `-1` on failure like `memory.grow` does.
+#[export_name = "__rs_wasm_alloc_grow"]
+pub extern "C" fn export_grow(pages: usize) -> isize {
+	grow(pages).map_or(-1, |previous| previous as isize)
+}
@@ -0,0 +1,19 @@
+//! Pages of linear memory, shared by the `memory` exports and the fallback
+//! heap.
+
+#![cfg_attr(
+	not(any(feature = "fallback", all(feature = "memory-exports", target_arch = "wasm32"))),
+	allow(dead_code)
+)]
+
+/// Size of a WebAssembly page, in bytes.
+pub const PAGE_SIZE: usize = 65536;
+
+/// Grows linear memory by `pages` pages, returning the previous size in
+/// pages, or `None` if memory could not be grown.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+pub fn grow(pages: usize) -> Option<usize> {
+	let previous = core::arch::wasm32::memory_grow(0, pages);
+	(previous != usize::MAX).then_some(previous)
+}
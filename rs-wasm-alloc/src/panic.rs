@@ -0,0 +1,484 @@
+//! Panic handler that reports to the host.
+//!
+//! The handler is generic over the [`PanicImports`] it reports to, so that
+//! [`define_extern_allocator!`](crate::define_extern_allocator) can install it
+//! over another module.
+
+use core::fmt::{
+	self,
+	Write
+};
+use core::marker::PhantomData;
+use core::panic::PanicInfo;
+#[cfg(feature = "panic-report")]
+use core::fmt::Display;
+use core::panic::Location;
+#[cfg(feature = "panic-report")]
+use core::ptr;
+use core::ptr::null_mut;
+use core::sync::atomic::{
+	AtomicBool,
+	AtomicPtr,
+	Ordering
+};
+
+use crate::{
+	AbortReason,
+	PayloadKind
+};
+#[cfg(feature = "panic-report")]
+use crate::text::Truncating;
+#[cfg(feature = "panic-handler")]
+use crate::imports::{
+	panic_ch,
+	panic_str
+};
+
+/// Writer that appends to the message of the host's pending panic.
+#[cfg(feature = "panic-handler")]
+#[derive(Debug)]
+pub struct Panic;
+
+#[cfg(feature = "panic-handler")]
+impl Write for Panic {
+	fn write_char(&mut self, ch: char) -> fmt::Result {
+		unsafe { panic_ch(ch as u32) };
+		Ok(())
+	}
+
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		unsafe { panic_str(s.as_ptr(), s.len()) };
+		Ok(())
+	}
+}
+
+/// Capacity of the static buffer that the panic handler formats a
+/// [`PanicReport`] into. Longer messages are truncated.
+#[cfg(feature = "panic-report")]
+pub const REPORT_CAPACITY: usize = 1024;
+
+/// Panic passed to the host in one call to `panic.panic_report`.
+///
+/// Strings are UTF-8 and borrowed from the guest for the duration of the call.
+#[cfg(feature = "panic-report")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanicReport {
+	pub message: *const u8,
+	pub message_len: usize,
+	/// Null if the location is unknown.
+	pub file: *const u8,
+	pub file_len: usize,
+	pub line: u32,
+	pub column: u32,
+	pub payload_kind: PayloadKind,
+	pub abort_reason: AbortReason
+}
+
+#[cfg(feature = "panic-report")]
+impl PanicReport {
+	/// Formats `message` into `buf`, truncating it at a `char` boundary if it
+	/// does not fit. The abort reason is that of [`AbortReason::of`].
+	pub fn new(
+		buf: &mut [u8],
+		message: impl Display,
+		payload_kind: PayloadKind,
+		location: Option<&Location<'_>>
+	) -> Self {
+		let mut writer = Truncating::new(buf);
+		let _ = write!(writer, "{message}");
+		let abort_reason = AbortReason::of(&message);
+
+		let (file, file_len) = location
+			.map_or((ptr::null(), 0), |location| {
+				(location.file().as_ptr(), location.file().len())
+			});
+		Self {
+			message: writer.as_str().as_ptr(),
+			message_len: writer.as_str().len(),
+			file,
+			file_len,
+			line: location.map_or(0, Location::line),
+			column: location.map_or(0, Location::column),
+			payload_kind,
+			abort_reason
+		}
+	}
+}
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the guest has panicked.
+#[inline]
+pub fn panicking() -> bool {
+	PANICKING.load(Ordering::Relaxed)
+}
+
+/// Hook that runs on panic, before the panic is reported to the host.
+pub type PanicHook = fn(&PanicInfo<'_>);
+
+static HOOK: AtomicPtr<()> = AtomicPtr::new(null_mut());
+static IN_HOOK: AtomicBool = AtomicBool::new(false);
+
+/// Sets the hook to run on panic, returning the previous one.
+///
+/// The hook runs at most once. If it panics, that panic is reported instead.
+pub fn set_panic_hook(hook: PanicHook) -> Option<PanicHook> {
+	unsafe { hook_from_ptr(HOOK.swap(hook as *mut (), Ordering::AcqRel)) }
+}
+
+/// Removes the hook to run on panic, returning it.
+pub fn take_panic_hook() -> Option<PanicHook> {
+	unsafe { hook_from_ptr(HOOK.swap(null_mut(), Ordering::AcqRel)) }
+}
+
+unsafe fn hook_from_ptr(ptr: *mut ()) -> Option<PanicHook> {
+	(!ptr.is_null()).then(|| core::mem::transmute::<*mut (), PanicHook>(ptr))
+}
+
+/// Takes the panic hook and passes it to `call`, which runs it.
+#[cfg_attr(any(test, not(target_arch = "wasm32")), allow(dead_code))]
+fn run_hook(call: impl FnOnce(PanicHook)) {
+	if let Some(hook) = take_panic_hook() {
+		IN_HOOK.store(true, Ordering::Relaxed);
+		call(hook);
+		IN_HOOK.store(false, Ordering::Relaxed);
+	}
+}
+
+/// Functions of a `panic` import module, that `handle_panic` reports to.
+pub trait PanicImports {
+	/// # Safety
+	/// The panic must not be finished already.
+	unsafe fn panic() -> !;
+	/// # Safety
+	/// `file` must point to `len` bytes of UTF-8.
+	unsafe fn panic_put_file(file: *const u8, len: usize);
+	fn panic_put_line_column(line: usize, col: usize);
+	fn panic_put_payload_kind(kind: u32);
+	fn panic_put_abort_reason(reason: u32);
+	/// # Safety
+	/// `str` must point to `len` bytes of UTF-8.
+	unsafe fn panic_str(str: *const u8, len: usize);
+	/// # Safety
+	/// `file` must point to `len` bytes of UTF-8.
+	unsafe fn panic_nested(file: *const u8, len: usize, line: u32) -> !;
+
+	/// Reports the panic of `info` and finishes it, over the functions above
+	/// unless overridden.
+	fn report(info: &PanicInfo<'_>) -> ! {
+		let message = info.message();
+		let mut writer = crate::Buffered::<_, 256>::new(PanicWriter::<Self>(PhantomData));
+		let _ = write!(writer, "{message}");
+		writer.send();
+		Self::panic_put_payload_kind(PayloadKind::of(&message) as u32);
+		Self::panic_put_abort_reason(AbortReason::of(&message) as u32);
+
+		if let Some(location) = info.location() {
+			let file = location.file();
+			unsafe { Self::panic_put_file(file.as_ptr(), file.len()) };
+			Self::panic_put_line_column(
+				location.line() as usize,
+				location.column() as usize
+			);
+		}
+
+		unsafe { Self::panic() }
+	}
+}
+
+/// Writer that appends to the message of the pending panic of `I`.
+struct PanicWriter<I: ?Sized>(PhantomData<I>);
+
+impl<I: PanicImports + ?Sized> Write for PanicWriter<I> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		unsafe { I::panic_str(s.as_ptr(), s.len()) };
+		Ok(())
+	}
+}
+
+/// Handles a panic of the guest by reporting it to `I`, after running the
+/// panic hook.
+#[cfg(target_arch = "wasm32")]
+pub fn handle_panic<I: PanicImports>(info: &PanicInfo<'_>) -> ! {
+	begin::<I>(info.location(), |hook| hook(info));
+	I::report(info)
+}
+
+/// Begins handling a panic at `location`, which is reported to `I` already if
+/// it is nested, and runs the hook with `run` otherwise.
+#[cfg_attr(any(test, not(target_arch = "wasm32")), allow(dead_code))]
+fn begin<I: PanicImports>(location: Option<&Location<'_>>, run: impl FnOnce(PanicHook)) {
+	// A panic while reporting a panic may have been caused by anything the
+	// report uses, so none of it is used again. Only a panic in the hook, which has
+	// been taken already, is reported like the first one.
+	if PANICKING.swap(true, Ordering::Relaxed) && !IN_HOOK.swap(false, Ordering::Relaxed) {
+		match location {
+			Some(location) => unsafe {
+				let file = location.file();
+				I::panic_nested(file.as_ptr(), file.len(), location.line())
+			},
+			None => trap()
+		}
+	}
+
+	run_hook(run)
+}
+
+/// Stops the guest without reporting anything.
+#[cfg_attr(any(test, not(target_arch = "wasm32")), allow(dead_code))]
+fn trap() -> ! {
+	#[cfg(target_arch = "wasm32")]
+	core::arch::wasm32::unreachable();
+	#[cfg(not(target_arch = "wasm32"))]
+	unreachable!("panicked while panicking without a location")
+}
+
+/// The host's `panic` module.
+#[cfg(all(feature = "panic-handler", target_arch = "wasm32", not(test)))]
+struct HostPanic;
+
+#[cfg(all(feature = "panic-handler", target_arch = "wasm32", not(test)))]
+impl PanicImports for HostPanic {
+	unsafe fn panic() -> ! {
+		crate::imports::panic()
+	}
+
+	unsafe fn panic_put_file(file: *const u8, len: usize) {
+		crate::imports::panic_put_file(file, len)
+	}
+
+	fn panic_put_line_column(line: usize, col: usize) {
+		unsafe { crate::imports::panic_put_line_column(line, col) }
+	}
+
+	fn panic_put_payload_kind(kind: u32) {
+		unsafe { crate::imports::panic_put_payload_kind(kind) }
+	}
+
+	fn panic_put_abort_reason(reason: u32) {
+		unsafe { crate::imports::panic_put_abort_reason(reason) }
+	}
+
+	unsafe fn panic_str(str: *const u8, len: usize) {
+		crate::imports::panic_str(str, len)
+	}
+
+	unsafe fn panic_nested(file: *const u8, len: usize, line: u32) -> ! {
+		crate::imports::panic_nested(file, len, line)
+	}
+
+	#[cfg(feature = "panic-report")]
+	fn report(info: &PanicInfo<'_>) -> ! {
+		static mut MESSAGE: [u8; REPORT_CAPACITY] = [0; REPORT_CAPACITY];
+
+		unsafe {
+			let buf = core::slice::from_raw_parts_mut(
+				(&raw mut MESSAGE).cast::<u8>(),
+				REPORT_CAPACITY
+			);
+			let message = info.message();
+			let report = PanicReport::new(
+				buf,
+				&message,
+				PayloadKind::of(&message),
+				info.location()
+			);
+			crate::imports::panic_report(&report)
+		}
+	}
+}
+
+#[cfg(all(feature = "panic-handler", target_arch = "wasm32", not(test)))]
+#[panic_handler]
+fn panic_handler(info: &PanicInfo<'_>) -> ! {
+	handle_panic::<HostPanic>(info)
+}
+
+#[cfg(all(test, not(target_arch = "wasm32"), feature = "panic-handler"))]
+mod tests {
+	extern crate std;
+
+	use std::boxed::Box;
+	use std::cell::RefCell;
+	use std::panic::AssertUnwindSafe;
+	use std::sync::{
+		Mutex,
+		MutexGuard,
+		PoisonError
+	};
+	use std::vec::Vec;
+
+	use super::*;
+	use crate::{
+		AbortReason,
+		PayloadKind
+	};
+	use crate::imports::{
+		panic,
+		panic_nested,
+		panic_put_abort_reason,
+		panic_put_file,
+		panic_put_line_column,
+		panic_put_payload_kind
+	};
+	use crate::mock::WasmPanic;
+	#[cfg(feature = "panic-report")]
+	use crate::imports::panic_report;
+
+	#[test]
+	fn host_panic_carries_message_and_location() {
+		let result = std::panic::catch_unwind(|| unsafe {
+			let (word, ch) = ("no", '!');
+			let _ = write!(Panic, "oh {word}{ch}");
+			let file = "src/main.rs";
+			panic_put_file(file.as_ptr(), file.len());
+			panic_put_line_column(4, 2);
+			panic_put_payload_kind(PayloadKind::Formatted as u32);
+			panic_put_abort_reason(AbortReason::Assertion as u32);
+			panic()
+		});
+		let payload = result.unwrap_err();
+		assert_eq!(payload.downcast_ref::<WasmPanic>(), Some(&WasmPanic {
+			message: "oh no!".into(),
+			file: Some("src/main.rs".into()),
+			line: 4,
+			column: 2,
+			payload_kind: PayloadKind::Formatted as u32,
+			abort_reason: AbortReason::Assertion as u32
+		}));
+	}
+
+	#[cfg(feature = "panic-report")]
+	#[test]
+	fn report_is_truncated_at_a_char_boundary() {
+		let mut buf = [0; 4];
+		let location = Location::caller();
+		let report = PanicReport::new(
+			&mut buf,
+			"ünïcode",
+			PayloadKind::Literal,
+			Some(location)
+		);
+		let result = std::panic::catch_unwind(|| unsafe { panic_report(&report) });
+		let payload = result.unwrap_err();
+		assert_eq!(payload.downcast_ref::<WasmPanic>(), Some(&WasmPanic {
+			message: "ün".into(),
+			file: Some(file!().into()),
+			line: location.line() as usize,
+			column: location.column() as usize,
+			payload_kind: PayloadKind::Literal as u32,
+			abort_reason: AbortReason::Panic as u32
+		}));
+	}
+
+	#[test]
+	fn nested_panics_are_reported_without_a_message() {
+		let _state = panic_state();
+		let location = Location::caller();
+		for expected in [Call::Panic, Call::Nested(location.line())] {
+			let calls = calls_until_panic(|| {
+				begin::<Recorder>(Some(location), |_| unreachable!());
+				unsafe { Recorder::panic() }
+			});
+			assert_eq!(calls, [expected]);
+		}
+
+		let result = std::panic::catch_unwind(|| unsafe {
+			let file = "src/main.rs";
+			panic_nested(file.as_ptr(), file.len(), 4)
+		});
+		let payload = result.unwrap_err();
+		assert_eq!(payload.downcast_ref::<WasmPanic>(), Some(&WasmPanic {
+			message: "panicked while panicking".into(),
+			file: Some("src/main.rs".into()),
+			line: 4,
+			column: 0,
+			payload_kind: PayloadKind::Unknown as u32,
+			abort_reason: AbortReason::Unknown as u32
+		}));
+	}
+
+	#[test]
+	fn panics_in_the_hook_are_reported_like_the_first() {
+		fn hook(_: &PanicInfo<'_>) {}
+
+		let _state = panic_state();
+		assert!(set_panic_hook(hook).is_none());
+		let location = Location::caller();
+		let calls = calls_until_panic(|| {
+			begin::<Recorder>(Some(location), |_| {
+				begin::<Recorder>(Some(location), |_| unreachable!());
+				unsafe { Recorder::panic() }
+			});
+			unsafe { Recorder::panic() }
+		});
+		assert_eq!(calls, [Call::Panic]);
+		assert!(take_panic_hook().is_none());
+
+		let calls = calls_until_panic(|| {
+			begin::<Recorder>(Some(location), |_| unreachable!());
+			unsafe { Recorder::panic() }
+		});
+		assert_eq!(calls, [Call::Nested(location.line())]);
+	}
+
+	/// Serializes the tests that panic through [`begin`], whose state is
+	/// global, and resets that state afterwards.
+	fn panic_state() -> impl Drop {
+		struct Reset {
+			_guard: MutexGuard<'static, ()>
+		}
+
+		impl Drop for Reset {
+			fn drop(&mut self) {
+				PANICKING.store(false, Ordering::Relaxed);
+				IN_HOOK.store(false, Ordering::Relaxed);
+				take_panic_hook();
+			}
+		}
+
+		static STATE: Mutex<()> = Mutex::new(());
+		Reset {
+			_guard: STATE.lock().unwrap_or_else(PoisonError::into_inner)
+		}
+	}
+
+	#[derive(Debug, PartialEq, Eq)]
+	enum Call {
+		Panic,
+		Nested(u32)
+	}
+
+	std::thread_local! {
+		static CALLS: RefCell<Vec<Call>> = const { RefCell::new(Vec::new()) };
+	}
+
+	/// Runs `f` until it panics, returning what it finished the panic with.
+	fn calls_until_panic(f: impl FnOnce()) -> Vec<Call> {
+		let result = std::panic::catch_unwind(AssertUnwindSafe(f));
+		assert!(result.is_err());
+		CALLS.take()
+	}
+
+	/// `panic` module that records how panics are finished.
+	struct Recorder;
+
+	impl PanicImports for Recorder {
+		unsafe fn panic() -> ! {
+			CALLS.with_borrow_mut(|calls| calls.push(Call::Panic));
+			std::panic::resume_unwind(Box::new(()))
+		}
+
+		unsafe fn panic_put_file(_: *const u8, _: usize) {}
+		fn panic_put_line_column(_: usize, _: usize) {}
+		fn panic_put_payload_kind(_: u32) {}
+		fn panic_put_abort_reason(_: u32) {}
+		unsafe fn panic_str(_: *const u8, _: usize) {}
+
+		unsafe fn panic_nested(_: *const u8, _: usize, line: u32) -> ! {
+			CALLS.with_borrow_mut(|calls| calls.push(Call::Nested(line)));
+			std::panic::resume_unwind(Box::new(()))
+		}
+	}
+}
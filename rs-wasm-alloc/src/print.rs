@@ -0,0 +1,180 @@
+//! Standard output and error streams over the host's `debug` module, for the
+//! [`guest_print!`](crate::guest_print) family of macros.
+//!
+//! [`print_with`] prints over any other module that implements
+//! [`DebugImports`].
+
+use core::fmt::{
+	self,
+	Write
+};
+use core::marker::PhantomData;
+
+use crate::{
+	Buffered,
+	DebugImports,
+	HostDebug
+};
+
+/// Output stream of the guest, numbered like the file descriptors.
+///
+/// Writes are flushed to the host at every newline, which is not passed on
+/// itself, so that the host gets one call per line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Stream {
+	Stdout = 1,
+	Stderr = 2
+}
+
+impl Stream {
+	#[inline]
+	pub fn flush(&mut self) {
+		HostDebug::dblog_flush_stream(*self as u32)
+	}
+}
+
+impl Write for Stream {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		Output::<HostDebug>(*self, PhantomData).write_str(s)
+	}
+}
+
+/// [`Stream`] of the debug module of `I`.
+struct Output<I>(Stream, PhantomData<fn() -> I>);
+
+impl<I: DebugImports> Output<I> {
+	#[inline]
+	fn write_raw(&self, s: &str) {
+		if !s.is_empty() {
+			unsafe { I::dblog_write(self.0 as u32, s.as_ptr(), s.len()) }
+		}
+	}
+}
+
+impl<I: DebugImports> Write for Output<I> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let mut lines = s.split('\n');
+		if let Some(first) = lines.next() {
+			self.write_raw(first);
+		}
+		for line in lines {
+			I::dblog_flush_stream(self.0 as u32);
+			self.write_raw(line);
+		}
+		Ok(())
+	}
+}
+
+/// Prints `args` to `stream` over the debug module of `I`, like the
+/// [`guest_print!`](crate::guest_print) family of macros does over the host's.
+pub fn print_with<I: DebugImports>(stream: Stream, args: fmt::Arguments<'_>) {
+	let _ = Buffered::<_, 256>::new(Output::<I>(stream, PhantomData)).write_fmt(args);
+}
+
+#[doc(hidden)]
+pub fn _print(stream: Stream, args: fmt::Arguments<'_>) {
+	print_with::<HostDebug>(stream, args)
+}
+
+/// Prints to the guest's standard output.
+#[macro_export]
+macro_rules! guest_print {
+	($($arg:tt)*) => {
+		$crate::print::_print(
+			$crate::print::Stream::Stdout,
+			::core::format_args!($($arg)*)
+		)
+	};
+}
+
+/// Prints to the guest's standard output, with a newline.
+#[macro_export]
+macro_rules! guest_println {
+	() => {
+		$crate::guest_print!("\n")
+	};
+	($($arg:tt)*) => {
+		$crate::print::_print(
+			$crate::print::Stream::Stdout,
+			::core::format_args!("{}\n", ::core::format_args!($($arg)*))
+		)
+	};
+}
+
+/// Prints to the guest's standard error.
+#[macro_export]
+macro_rules! guest_eprint {
+	($($arg:tt)*) => {
+		$crate::print::_print(
+			$crate::print::Stream::Stderr,
+			::core::format_args!($($arg)*)
+		)
+	};
+}
+
+/// Prints to the guest's standard error, with a newline.
+#[macro_export]
+macro_rules! guest_eprintln {
+	() => {
+		$crate::guest_eprint!("\n")
+	};
+	($($arg:tt)*) => {
+		$crate::print::_print(
+			$crate::print::Stream::Stderr,
+			::core::format_args!("{}\n", ::core::format_args!($($arg)*))
+		)
+	};
+}
+
+/// Prints and returns the value of an expression to the guest's standard
+/// error, like `std::dbg!`.
+#[macro_export]
+macro_rules! guest_dbg {
+	() => {
+		$crate::guest_eprintln!(
+			"[{}:{}:{}]",
+			::core::file!(), ::core::line!(), ::core::column!()
+		)
+	};
+	($val:expr $(,)?) => {
+		match $val {
+			tmp => {
+				$crate::guest_eprintln!(
+					"[{}:{}:{}] {} = {:#?}",
+					::core::file!(), ::core::line!(), ::core::column!(),
+					::core::stringify!($val), &tmp
+				);
+				tmp
+			}
+		}
+	};
+	($($val:expr),+ $(,)?) => {
+		($($crate::guest_dbg!($val)),+,)
+	};
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+	extern crate std;
+
+	use std::format;
+
+	use crate::mock;
+
+	#[test]
+	fn lines_are_flushed_per_stream() {
+		guest_print!("a");
+		guest_eprintln!("oops");
+		guest_println!("b\nc");
+		let (value, line) = (guest_dbg!(1 + 1), line!());
+		guest_print!("pending");
+
+		assert_eq!(value, 2);
+		assert_eq!(mock::take_stream_lines(1), ["ab", "c"]);
+		assert_eq!(mock::take_stream_lines(2), [
+			"oops",
+			&format!("[{}:{line}:24] 1 + 1 = 2", file!())
+		]);
+	}
+}
@@ -0,0 +1,87 @@
+//! Allocation statistics kept by [`ExternAllocator`](crate::ExternAllocator).
+
+use core::sync::atomic::{
+	AtomicUsize,
+	Ordering
+};
+
+/// Snapshot of the allocation statistics of the guest.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocStats {
+	/// Bytes currently allocated.
+	pub live_bytes: usize,
+	/// Highest value `live_bytes` has reached.
+	pub peak_bytes: usize,
+	/// Successful allocations, including zeroed ones.
+	pub allocs: usize,
+	pub frees: usize,
+	/// Successful reallocations.
+	pub reallocs: usize,
+	/// Calls made to the host's `alloc` module, including failed ones.
+	pub host_calls: usize
+}
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+static REALLOCS: AtomicUsize = AtomicUsize::new(0);
+static HOST_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+impl AllocStats {
+	pub fn get() -> Self {
+		Self {
+			live_bytes: LIVE_BYTES.load(Ordering::Relaxed),
+			peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+			allocs: ALLOCS.load(Ordering::Relaxed),
+			frees: FREES.load(Ordering::Relaxed),
+			reallocs: REALLOCS.load(Ordering::Relaxed),
+			host_calls: HOST_CALLS.load(Ordering::Relaxed)
+		}
+	}
+}
+
+fn grow_live_bytes(by: usize) {
+	let live_bytes = LIVE_BYTES.fetch_add(by, Ordering::Relaxed) + by;
+	PEAK_BYTES.fetch_max(live_bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn record_host_call() {
+	HOST_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_alloc(ptr: *mut u8, size: usize) {
+	record_host_call();
+	if !ptr.is_null() {
+		ALLOCS.fetch_add(1, Ordering::Relaxed);
+		grow_live_bytes(size);
+	}
+}
+
+pub(crate) fn record_dealloc(size: usize) {
+	record_host_call();
+	FREES.fetch_add(1, Ordering::Relaxed);
+	LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub(crate) fn record_realloc(ptr: *mut u8, size: usize, new_size: usize) {
+	record_host_call();
+	if !ptr.is_null() {
+		REALLOCS.fetch_add(1, Ordering::Relaxed);
+		if new_size >= size {
+			grow_live_bytes(new_size - size);
+		} else {
+			LIVE_BYTES.fetch_sub(size - new_size, Ordering::Relaxed);
+		}
+	}
+}
+
+/// Export of [`AllocStats::get`], writing the statistics to `stats`.
+///
+/// # Safety
+/// `stats` must be valid for writing an [`AllocStats`].
+#[export_name = "__rs_wasm_alloc_stats"]
+pub unsafe extern "C" fn export_stats(stats: *mut AllocStats) {
+	stats.write(AllocStats::get())
+}
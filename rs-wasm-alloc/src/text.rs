@@ -0,0 +1,57 @@
+//! UTF-8 helpers shared by the panic report and the guest-side imports.
+
+#[cfg(any(feature = "panic-report", all(target_arch = "wasm32", feature = "wasi")))]
+use core::fmt::{
+	self,
+	Write
+};
+
+/// Borrows `len` bytes at `ptr` as a string.
+///
+/// # Safety
+/// `ptr` must point to `len` bytes of UTF-8 that live for `'a`.
+#[cfg(any(not(target_arch = "wasm32"), feature = "wasi"))]
+pub(crate) unsafe fn str_from_raw<'a>(ptr: *const u8, len: usize) -> &'a str {
+	core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
+}
+
+/// Writer into a fixed buffer that truncates at a `char` boundary, and fails,
+/// once it is full.
+#[cfg(any(feature = "panic-report", all(target_arch = "wasm32", feature = "wasi")))]
+pub(crate) struct Truncating<B> {
+	buf: B,
+	len: usize
+}
+
+#[cfg(any(feature = "panic-report", all(target_arch = "wasm32", feature = "wasi")))]
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Truncating<B> {
+	pub(crate) const fn new(buf: B) -> Self {
+		Self {
+			buf,
+			len: 0
+		}
+	}
+
+	pub(crate) fn as_str(&self) -> &str {
+		unsafe { core::str::from_utf8_unchecked(&self.buf.as_ref()[..self.len]) }
+	}
+
+	#[cfg(all(target_arch = "wasm32", feature = "wasi", feature = "debug-log"))]
+	pub(crate) fn clear(&mut self) {
+		self.len = 0;
+	}
+}
+
+#[cfg(any(feature = "panic-report", all(target_arch = "wasm32", feature = "wasi")))]
+impl<B: AsRef<[u8]> + AsMut<[u8]>> Write for Truncating<B> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let buf = self.buf.as_mut();
+		let mut end = s.len().min(buf.len() - self.len);
+		while !s.is_char_boundary(end) {
+			end -= 1;
+		}
+		buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+		self.len += end;
+		if end < s.len() { Err(fmt::Error) } else { Ok(()) }
+	}
+}